@@ -1,402 +1,765 @@
-use std::{env::{self}, path::{absolute, PathBuf}, process::{Command, Stdio}, str::FromStr, thread::sleep, time::Duration};
+use std::{ffi::OsString, fs, io::IsTerminal, path::{absolute, Path, PathBuf}, process::{Command, Stdio}};
 
-use crate::FlashCmdDescriptor;
+use log::{debug, info, warn};
+use serde::Deserialize;
 
+use crate::{chip::{chip, Chip, RUST_TARGET}, config::{self, ConfigError, ProjectConfig}, elf, events::{self, Event}, exit_code, flasher::{run_in_ram, select_flasher}, ihex::{self, HexImage}, probe_lock::{self, ProbeLock}, process::{self, StderrTail, Supervised}, server::Session, tools::{self, Tool, ToolError}, BootMode, FlashCmdDescriptor, ObjcopyTool};
+
+
+/// Failure of `run`. Variants carry what is needed to understand the failure without rerunning:
+/// the tool, the path, and the last lines of the tool's stderr where there is one.
 #[derive(Debug)]
 pub enum RunError {
-    PackageNotInstalled,
-    ObjcopyFailed,
-    UploadFailed,
-    NoGdbExec,
-    GdbFailed,
-    NotAProject,
+    NotAProject(PathBuf),
+    BadConfig(ConfigError),
+    BadArgument(String),
+    Tool(ToolError),
+    Io { path: PathBuf, error: std::io::Error },
+    BuildFailed { reason: String, stderr: String },
+    BadImage { path: PathBuf, reason: String },
+    BootModeMismatch(String),
+    ProbeNotFound { reason: String, stderr: String },
+    UploadFailed { tool: &'static str, reason: String, stderr: String },
+    GdbFailed { reason: String, stderr: String },
+    ProbeBusy { probe: String, holder: Option<u32> },
 }
 
-fn command_exists(cmd: &str) -> bool {
-    Command::new(cmd)
-        .arg("--version") // любой аргумент, который не сломает команду
-        .stdout(std::process::Stdio::null())
-        .stderr(std::process::Stdio::null())
-        .status()
-        .is_ok()
+impl RunError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RunError::NotAProject(_) | RunError::BadArgument(_) => exit_code::USAGE,
+            RunError::BadConfig(e) => e.exit_code(),
+            RunError::Tool(_) => exit_code::TOOL_MISSING,
+            RunError::Io { .. } => exit_code::IO,
+            RunError::BuildFailed { .. } => exit_code::BUILD_FAILED,
+            RunError::BadImage { .. } | RunError::BootModeMismatch(_) => exit_code::BAD_IMAGE,
+            RunError::ProbeNotFound { .. } => exit_code::PROBE_NOT_FOUND,
+            RunError::UploadFailed { .. } => exit_code::UPLOAD_FAILED,
+            RunError::GdbFailed { .. } => exit_code::GDB_FAILED,
+            RunError::ProbeBusy { .. } => exit_code::PROBE_BUSY,
+        }
+    }
+
+    pub fn io(path: &Path, error: std::io::Error) -> Self {
+        RunError::Io { path: path.to_path_buf(), error }
+    }
 }
 
-/// Perform objcopy operation from cargo-binutils. If app_hex_path is provided with flag reuse procedure will check its existance.
-/// If app_hex_path provided without reuse procedure will build the app in destination.
-/// If no app_hex_path provided procedure will build app in default destination ./flash/app.hex.
-fn objcopy(
-    app_hex_path: &mut Option<PathBuf>,
-    reuse: bool,
-    example: Option<String>,
-    project_dir: &PathBuf, 
-) -> Result<(), RunError> 
-{
-    if app_hex_path.is_some() && reuse{
-        if !absolute(app_hex_path.clone().unwrap()).unwrap().exists() {
-            eprintln!("Binary hex path was provided with reuse flag. However, the binary seems to not exist. Build the binary first.");
-            return Err(RunError::ObjcopyFailed);
+impl std::fmt::Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::NotAProject(dir) => write!(f, "{} is not a cargo project, Cargo.toml not found", dir.display()),
+            RunError::BadConfig(e) => write!(f, "bad project configuration, {}", e),
+            RunError::BadArgument(e) => write!(f, "{}", e),
+            RunError::Tool(e) => write!(f, "{}. Run 'cargo mik32 doctor' for hints", e),
+            RunError::Io { path, error } => write!(f, "failed to access {}, {}", path.display(), error),
+            RunError::BuildFailed { reason, stderr } => {
+                write!(f, "build failed, {}", reason)?;
+                write_stderr(f, "cargo", stderr)
+            }
+            RunError::BadImage { path, reason } => write!(f, "bad image {}, {}", path.display(), reason),
+            RunError::BootModeMismatch(reason) => write!(f, "{}", reason),
+            RunError::ProbeNotFound { reason, stderr } => {
+                write!(f, "probe or target not found, {}", reason)?;
+                write_stderr(f, "openocd", stderr)
+            }
+            RunError::UploadFailed { tool, reason, stderr } => {
+                write!(f, "upload with {} failed, {}", tool, reason)?;
+                write_stderr(f, tool, stderr)
+            }
+            RunError::GdbFailed { reason, stderr } => {
+                write!(f, "gdb session failed, {}", reason)?;
+                write_stderr(f, "gdb", stderr)
+            }
+            RunError::ProbeBusy { probe, holder } => write!(
+                f,
+                "probe {} is in use by {}, pass --wait to wait until it is released",
+                probe,
+                probe_lock::describe_holder(*holder)
+            ),
         }
-        return Ok(())
     }
+}
 
-    if !command_exists("cargo-objcopy") {
-        eprintln!("
-                cargo-objcopy not found. Install it:\n
-                cargo install cargo-binutils\n
-                rustup component add llvm-tools-preview"
-            );
-        return Err(RunError::PackageNotInstalled);
+fn write_stderr(f: &mut std::fmt::Formatter<'_>, tool: &str, stderr: &str) -> std::fmt::Result {
+    if stderr.is_empty() {
+        return Ok(());
     }
+    write!(f, "\n{} stderr (last lines):\n{}", tool, stderr)
+}
 
-    
+/// Executable reported by cargo in a `compiler-artifact` message.
+struct Artifact {
+    name: String,
+    executable: PathBuf,
+}
 
-    let app_path: PathBuf;
-    if app_hex_path.is_some() {
-        app_path = app_hex_path.clone().unwrap();
-    } else {
-        app_path = project_dir.join("flash").join("app.hex");
+#[derive(Deserialize)]
+struct CargoMessage {
+    reason: String,
+    target: Option<CargoTarget>,
+    executable: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct CargoTarget {
+    name: String,
+    kind: Vec<String>,
+}
+
+/// Package, target, profile and feature selection shared by `cargo build` and `cargo objcopy`.
+/// Profile defaults to release. CARGO_TARGET_DIR is picked up by cargo itself.
+fn cargo_selection_args(desc: &FlashCmdDescriptor) -> Vec<OsString> {
+    let mut args: Vec<OsString> = Vec::new();
+    match &desc.profile {
+        Some(profile) => args.extend(["--profile".into(), profile.into()]),
+        None => args.push("--release".into()),
+    }
+    if let Some(package) = &desc.package {
+        args.extend(["--package".into(), package.into()]);
+    }
+    if let Some(bin) = &desc.bin {
+        args.extend(["--bin".into(), bin.into()]);
+    }
+    if let Some(example) = &desc.example {
+        args.extend(["--example".into(), example.into()]);
+    }
+    for features in &desc.features {
+        args.extend(["--features".into(), features.into()]);
+    }
+    if desc.no_default_features {
+        args.push("--no-default-features".into());
     }
+    if let Some(target_dir) = &desc.target_dir {
+        // absolute() only fails on an empty path, which cargo reports itself.
+        args.extend(["--target-dir".into(), absolute(target_dir).unwrap_or(target_dir.clone()).into()]);
+    }
+    // Diagnostics are piped through us, keep them colored on a terminal.
+    if std::io::stderr().is_terminal() {
+        args.push("--color=always".into());
+    }
+    args
+}
 
+/// Runs `cargo build` with JSON messages and picks the executable of the selected bin or example,
+/// or the only binary that was built. Diagnostics are still rendered to stderr by cargo.
+fn cargo_build(desc: &FlashCmdDescriptor) -> Result<Artifact, RunError> {
     let mut build = Command::new("cargo");
     build.arg("build");
-    build.arg("-release");
+    build.arg("--message-format=json-render-diagnostics");
+    build.args(cargo_selection_args(desc));
 
+    if desc.dry_run {
+        events::dry_run("cargo", &process::describe(&build));
+        return planned_artifact(desc);
+    }
+
+    info!("Building...");
+    debug!("Running {}", process::describe(&build));
+    events::emit(Event::BuildStarted);
+    let mut child = Supervised::spawn(build.stdout(Stdio::piped()).stderr(Stdio::piped()))
+        .map_err(|e| RunError::BuildFailed { reason: format!("failed to run cargo, {}", e), stderr: String::new() })?;
+    let stderr = StderrTail::capture(&mut child, "cargo", true);
+    let output = child
+        .wait_with_output()
+        .map_err(|e| RunError::BuildFailed { reason: format!("failed to wait for cargo, {}", e), stderr: String::new() })?;
+    let stderr = stderr.finish();
+    events::emit(Event::BuildFinished { success: output.status.success() });
+    if !output.status.success() {
+        return Err(RunError::BuildFailed { reason: format!("cargo build exited with {}", output.status), stderr });
+    }
+    select_artifact(&String::from_utf8_lossy(&output.stdout), desc)
+}
+
+/// Picks the executable of the selected bin or example from cargo JSON messages, or the only binary built.
+/// Fresh artifacts, not rebuilt by this run, are reported by cargo as well.
+fn select_artifact(messages: &str, desc: &FlashCmdDescriptor) -> Result<Artifact, RunError> {
+    let (wanted_kind, wanted_name) = match (&desc.example, &desc.bin) {
+        (Some(example), _) => ("example", Some(example.as_str())),
+        (None, bin) => ("bin", bin.as_deref()),
+    };
+    let artifacts: Vec<Artifact> = messages
+        .lines()
+        .filter_map(|line| serde_json::from_str::<CargoMessage>(line).ok())
+        .filter(|msg| msg.reason == "compiler-artifact")
+        .filter_map(|msg| {
+            let target = msg.target?;
+            if !target.kind.iter().any(|kind| kind == wanted_kind) {
+                return None;
+            }
+            if wanted_name.is_some_and(|name| name != target.name) {
+                return None;
+            }
+            Some(Artifact { name: target.name, executable: msg.executable? })
+        })
+        .collect();
+
+    match artifacts.len() {
+        1 => Ok(artifacts.into_iter().next().unwrap()),
+        0 => Err(RunError::BuildFailed {
+            reason: format!("cargo did not report any {} executable", wanted_kind),
+            stderr: String::new(),
+        }),
+        _ => {
+            let names: Vec<&str> = artifacts.iter().map(|a| a.name.as_str()).collect();
+            Err(RunError::BuildFailed {
+                reason: format!("cargo built several executables ({}), select one with --bin or --package", names.join(", ")),
+                stderr: String::new(),
+            })
+        }
+    }
+}
+
+/// Executable `cargo build` is expected to produce, for `--dry-run`. Mirrors cargo's layout:
+/// <target-dir>/<target>/<profile dir>/[examples/]<name>. Workspace members may place it elsewhere.
+fn planned_artifact(desc: &FlashCmdDescriptor) -> Result<Artifact, RunError> {
+    let name = desc.example
+        .clone()
+        .or(desc.bin.clone())
+        .or(desc.package.clone())
+        .or_else(|| config::package_name(&desc.project_dir))
+        .ok_or_else(|| RunError::BadArgument("cannot tell which binary would be built, pass --bin or --package".to_owned()))?;
+    let target_dir = desc.target_dir
+        .clone()
+        .or(std::env::var_os("CARGO_TARGET_DIR").map(PathBuf::from))
+        .unwrap_or(desc.project_dir.join("target"));
+    let profile = match desc.profile.as_deref() {
+        None => "release",
+        Some("dev" | "test") => "debug",
+        Some("bench") => "release",
+        Some(profile) => profile,
+    };
+    let mut executable = target_dir.join(RUST_TARGET).join(profile);
+    if desc.example.is_some() {
+        executable.push("examples");
+    }
+    executable.push(&name);
+    Ok(Artifact { name, executable })
+}
+
+/// Produce hex image of the application. If app_hex_path is provided with flag reuse procedure will check its existance.
+/// Otherwise the app is built and the ELF reported by cargo is converted into app_hex_path,
+/// or into ./flash/<bin-or-example>.hex when no path was given.
+/// Returns the hex path, parsed image and the ELF, if it was built.
+/// With `--dry-run` nothing is built or written and the image is only returned when a previous build left one.
+/// By default the image is made from the ELF directly, `--objcopy cargo` switches to cargo-binutils.
+fn objcopy(desc: &FlashCmdDescriptor) -> Result<(PathBuf, Option<HexImage>, Option<PathBuf>), RunError> {
+    if let Some(app_hex_path) = &desc.app_hex_path && desc.reuse {
+        let app_hex_path = absolute(app_hex_path).map_err(|e| RunError::io(app_hex_path, e))?;
+        if !app_hex_path.exists() {
+            return Err(RunError::BadImage {
+                path: app_hex_path,
+                reason: "file does not exist, build the binary first or drop --reuse".to_owned(),
+            });
+        }
+        let image = check_hex(&app_hex_path, chip(desc.mcu_type.as_ref()))?;
+        return Ok((app_hex_path, Some(image), None));
+    }
+
+    if matches!(desc.objcopy, Some(ObjcopyTool::Cargo)) {
+        find_tool(Tool::Objcopy, None, &desc.project_dir)?;
+    }
+
+    let artifact = cargo_build(desc)?;
+    if !desc.dry_run {
+        info!("Built {}", artifact.executable.display());
+        events::emit(Event::Artifact { name: &artifact.name, executable: events::path(&artifact.executable) });
+    }
+
+    let app_path = match &desc.app_hex_path {
+        Some(path) => absolute(path).map_err(|e| RunError::io(path, e))?,
+        None => desc.project_dir.join("flash").join(format!("{}.hex", artifact.name)),
+    };
+
+    if desc.dry_run {
+        match desc.objcopy {
+            Some(ObjcopyTool::Cargo) => events::dry_run("cargo objcopy", &process::describe(&cargo_objcopy_command(&app_path, desc))),
+            _ => events::dry_run(
+                "objcopy",
+                &format!("convert {} into {} (built-in)", artifact.executable.display(), app_path.display()),
+            ),
+        }
+        let image = match app_path.exists() {
+            true => Some(check_hex(&app_path, chip(desc.mcu_type.as_ref()))?),
+            false => None,
+        };
+        return Ok((app_path, image, Some(artifact.executable)));
+    }
+
+    if let Some(parent) = app_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| RunError::io(parent, e))?;
+    }
+
+    match desc.objcopy {
+        Some(ObjcopyTool::Cargo) => cargo_objcopy(&app_path, desc)?,
+        _ => native_objcopy(&app_path, &artifact.executable)?,
+    }
+
+    let image = check_hex(&app_path, chip(desc.mcu_type.as_ref()))?;
+    Ok((app_path, Some(image), Some(artifact.executable)))
+}
+
+fn cargo_objcopy_command(app_path: &Path, desc: &FlashCmdDescriptor) -> Command {
     let mut objcopy = Command::new("cargo");
     objcopy.arg("objcopy");
-    objcopy.arg("--release");
-    if example.is_some() {
-        objcopy.args([
-            "--example",
-            &example.unwrap()
-        ]);
-    }
-    objcopy.args([
-        "--",
-        "-O",
-        "ihex",
-        absolute(app_path.clone()).unwrap().to_str().unwrap()
-    ]);
-    
-    let obcp_stat = objcopy.status().expect("Failed to run objcopy");
-    if !obcp_stat.success() {
-        eprintln!("Objcopy failed due to error");
-        return Err(RunError::ObjcopyFailed);
-    }
-    *app_hex_path = Some(absolute(app_path).unwrap());
+    objcopy.args(cargo_selection_args(desc));
+    objcopy.args(["--", "-O", "ihex"]);
+    objcopy.arg(app_path);
+    objcopy
+}
+
+fn cargo_objcopy(app_path: &Path, desc: &FlashCmdDescriptor) -> Result<(), RunError> {
+    let mut objcopy = cargo_objcopy_command(app_path, desc);
+
+    debug!("Running {}", process::describe(&objcopy));
+    let mut child = Supervised::spawn(objcopy.stderr(Stdio::piped()))
+        .map_err(|e| RunError::BuildFailed { reason: format!("failed to run cargo objcopy, {}", e), stderr: String::new() })?;
+    let stderr = StderrTail::capture(&mut child, "cargo", true);
+    let status = child.wait().map_err(|e| RunError::BuildFailed { reason: e.to_string(), stderr: String::new() })?;
+    let stderr = stderr.finish();
+    if !status.success() {
+        return Err(RunError::BuildFailed { reason: format!("cargo objcopy exited with {}", status), stderr });
+    }
     Ok(())
 }
 
+/// Converts loadable segments of the ELF into hex without cargo-binutils.
+/// A flat binary is written next to the hex file when the image is compact enough.
+fn native_objcopy(app_path: &Path, elf_path: &Path) -> Result<(), RunError> {
+    info!("Converting {}", elf_path.display());
+    let image = elf::read_file(elf_path)
+        .map_err(|e| RunError::BadImage { path: elf_path.to_path_buf(), reason: e.to_string() })?;
+
+    image.write_file(app_path).map_err(|e| match e {
+        ihex::HexError::Io(e) => RunError::io(app_path, e),
+        e => RunError::BadImage { path: app_path.to_path_buf(), reason: e.to_string() },
+    })?;
+
+    let bin_path = app_path.with_extension("bin");
+    match image.to_binary() {
+        Some(binary) => fs::write(&bin_path, binary).map_err(|e| RunError::io(&bin_path, e))?,
+        None => info!(
+            "Skipping {}: image spans more than {} MiB",
+            bin_path.display(),
+            ihex::MAX_BINARY_SPAN / 1024 / 1024
+        ),
+    }
+    Ok(())
+}
 
-///Upload procedure accepts openocd path as well as uploader path, to initiate upload script.
-///If no uploader path was provided it will seek it in MIK32_UPLOADER_PATH env variable and in project folder and in case of failure will panic.
-///Whereas, if no openocd path was provided it will seek it in env variable MIK32_OPENOCD_PATH and then via which command.
-///Also it checks mik32_upload.py existance.
-fn upload(
-    openocd_path: Option<PathBuf>,
-    uploader_path: Option<PathBuf>,
-    app_hex_path: Option<PathBuf>,
-    use_quad_spi: bool,
-    openocd_host: Option<String>,
-    openocd_port: Option<String>,
-    adapter_speed: Option<String>,
-    openocd_scripts: Option<PathBuf>,
-    openocd_interface: Option<PathBuf>,
-    openocd_target: Option<PathBuf>,
-    project_dir: &PathBuf
-    
-) -> Result<(), RunError> 
-{
-    let mut uploader_final_path: Option<PathBuf> = None;
-    let mut openocd_final_path: Option<PathBuf> = None;
-    println!("Fetching mik32 uploader path...");
-
-    let mut uploader_fetch_success = false;
-    if uploader_path.is_none() {
-        println!("Fetching uplaoder path from MIK32_UPLOADER_PATH variable...");
-        match env::var("MIK32_UPLOADER_PATH") {
-            Ok(path) => {
-                uploader_final_path = Some(PathBuf::from_str(&path)
-                    .expect("Failed to make PathBuf out of environment variable"));
-                uploader_fetch_success = true
-            }
-            Err(e) => {
-                eprintln!("Fetching from MIK32_UPLOADER_PATH environment variable failed. Consider setting it to desired path. {}", e);
-            }
+/// Parses the hex image before upload, so a broken or empty file is caught before any tool touches the board.
+/// Prints a short memory map of the image.
+fn check_hex(app_hex_path: &Path, chip: &Chip) -> Result<HexImage, RunError> {
+    info!("Validating {}", app_hex_path.display());
+    let image = ihex::read_file(app_hex_path)
+        .map_err(|e| RunError::BadImage { path: app_hex_path.to_path_buf(), reason: e.to_string() })?;
+    if image.segments.is_empty() {
+        return Err(RunError::BadImage { path: app_hex_path.to_path_buf(), reason: "no data".to_owned() });
+    }
+
+    for segment in &image.segments {
+        debug!("  0x{:08x}..0x{:08x} {} bytes", segment.address, segment.end(), segment.data.len());
+    }
+    let usage: Vec<String> = chip
+        .regions()
+        .iter()
+        .map(|region| {
+            let bytes: usize = image.segments_in(region.start, region.end()).iter().map(|s| s.data.len()).sum();
+            format!("{} {}", region.name, bytes)
+        })
+        .collect();
+    info!("Image size: {} bytes, {}: {}", image.size(), chip.name, usage.join(", "));
+    Ok(image)
+}
+
+/// Checks that the image fits the memory of the requested boot mode.
+/// Without a boot mode, or with 'undefined', the mode is inferred from the addresses the image occupies.
+fn resolve_boot_mode(boot_mode: Option<&BootMode>, image: &HexImage, chip: &Chip) -> Result<BootMode, RunError> {
+    let boot_mode = match boot_mode {
+        Some(BootMode::Undefined) | None => {
+            let mode = infer_boot_mode(image, chip)?;
+            info!("Boot mode inferred from image addresses: {}", boot_mode_name(&mode));
+            mode
         }
-    } 
-    if uploader_path.is_none() && !uploader_fetch_success {
-        println!("Fetching uploader path from project directory...");
-        let uploader_project_path = project_dir
-            .join("flash")
-            .join("mik32-uploader");
-        if !uploader_project_path.exists() {
-            eprintln!("Fetching from project directory failed. Consider cloning uploader to ./flash directory or setting MIK32_UPLOADER_PATH to desired value");
-            return Err(RunError::UploadFailed);
+        Some(mode) => mode.clone(),
+    };
+
+    let region = chip.boot_region(&boot_mode).unwrap();
+    if let Some(segment) = image
+        .segments
+        .iter()
+        .find(|s| !region.contains(s.address) || s.end() > region.end())
+    {
+        return Err(RunError::BootModeMismatch(format!(
+            "image data at 0x{:08x}..0x{:08x} does not fit {} (0x{:08x}..0x{:08x}), check linker script and --boot-mode",
+            segment.address,
+            segment.end(),
+            region.name,
+            region.start,
+            region.end()
+        )));
+    }
+    Ok(boot_mode)
+}
+
+/// Boot mode whose memory holds every segment of the image. Data in two memories, or outside all of them, is refused.
+fn infer_boot_mode(image: &HexImage, chip: &Chip) -> Result<BootMode, RunError> {
+    let mut inferred: Option<BootMode> = None;
+    for segment in &image.segments {
+        let Some(mode) = chip.boot_mode_of(segment.address) else {
+            return Err(RunError::BootModeMismatch(format!(
+                "image data at 0x{:08x}..0x{:08x} is neither in EEPROM, RAM nor SPIFI",
+                segment.address,
+                segment.end()
+            )));
+        };
+        match &inferred {
+            Some(first) if boot_mode_name(first) != boot_mode_name(&mode) => {
+                return Err(RunError::BootModeMismatch(format!(
+                    "image has data in both {} and {} (0x{:08x}), the boot mode can not be inferred, check linker script",
+                    chip.boot_region(first).unwrap().name,
+                    chip.boot_region(&mode).unwrap().name,
+                    segment.address
+                )));
+            }
+            Some(_) => {}
+            None => inferred = Some(mode),
         }
-        uploader_final_path = Some(uploader_project_path);
     }
+    inferred.ok_or_else(|| RunError::BootModeMismatch("image has no data to infer the boot mode from".to_owned()))
+}
 
-    if uploader_path.is_some() {
-        println!("Fetching uploader path from provided value...");
-        uploader_final_path = uploader_path.clone();
+/// Command line spelling of the boot mode, as used in messages and by mik32_upload.py.
+pub fn boot_mode_name(boot_mode: &BootMode) -> &'static str {
+    match boot_mode {
+        BootMode::Undefined => "undefined",
+        BootMode::Eeprom => "eeprom",
+        BootMode::Ram => "ram",
+        BootMode::Spifi => "spifi",
     }
+}
+
+/// Resolves a tool for `run` and reports which one is used. See `tools::resolve` for the search order.
+pub fn find_tool(tool: Tool, provided: Option<&Path>, project_dir: &Path) -> Result<PathBuf, RunError> {
+    let resolved = tools::resolve(tool, provided, project_dir).map_err(RunError::Tool)?;
+    debug!(
+        "Using {} {} ({}{})",
+        tool.name(),
+        resolved.path.display(),
+        resolved.source,
+        resolved.version.as_ref().map(|v| format!(", {}", v)).unwrap_or_default()
+    );
+    events::emit(Event::ToolResolved {
+        tool: tool.name(),
+        path: events::path(&resolved.path),
+        source: resolved.source.to_string(),
+        version: resolved.version.as_deref(),
+    });
+    Ok(resolved.path)
+}
+
+/// Internal gdb script: memory map of the chip, connection to openocd and target preparation.
+fn gdb_script(desc: &FlashCmdDescriptor, host: &str, gdb_port: u16) -> String {
+    let chip = chip(desc.mcu_type.as_ref());
+    // Flash memories were already programmed by the upload step, gdb only writes RAM images.
+    // `load` sets pc to the ELF entry, stack is put at the top of RAM like the startup code expects.
+    let startup = match desc.boot_mode {
+        Some(BootMode::Ram) => format!("monitor reset halt\nload\nset $sp = 0x{:08x}\n", chip.ram.end()),
+        _ => "monitor reset halt\n".to_owned(),
+    };
+    chip.gdb_memory_map()
+        + "set arch riscv:rv32\n"
+        + "set remotetimeout 10\n"
+        + "set remote hardware-breakpoint-limit 2\n"
+        + &format!("target remote {}:{}\n", host, gdb_port)
+        + &startup
+}
+
+/// gdb runs after upload with `--gdb`, or when one is named by `--gdb-exec`, `gdb-exec` in the project config or MIK32_GDB_EXEC.
+fn debug_requested(desc: &FlashCmdDescriptor) -> bool {
+    desc.gdb
+        || desc.gdb_exec.is_some()
+        || Tool::Gdb.env_var().and_then(std::env::var_os).is_some_and(|value| !value.is_empty())
+}
 
-    if uploader_final_path.is_none() {
-        eprintln!("Failed to fetch uploader path due to unexpected error...");
-        return Err(RunError::UploadFailed);
+/// Opens an openocd session for gdb and runs gdb with the internal script. Skipped when no gdb was requested.
+fn connect_gdb(desc: &FlashCmdDescriptor) -> Result<(), RunError>{
+    if !debug_requested(desc) {
+        info!("gdb was not requested. Skipping this step.");
+        return Ok(());
     }
+    let gdb_path = find_tool(Tool::Gdb, desc.gdb_exec.as_deref().map(Path::new), &desc.project_dir)?;
+    let Some(t_path) = &desc.gdb_target_path else {
+        return Err(RunError::GdbFailed {
+            reason: "no ELF to debug, pass --gdb-target-path".to_owned(),
+            stderr: String::new(),
+        });
+    };
+    let t_path = absolute(t_path).map_err(|e| RunError::io(t_path, e))?;
+
+    let openocd = Session::open(desc, true)?;
+    // The script goes to a file, stdin stays with the terminal so the gdb console is interactive.
+    let script_dir = config::runtime_dir().map_err(|e| RunError::io(Path::new("runtime directory"), e))?;
+    let script_path = script_dir.join(format!("gdb-{}.gdb", std::process::id()));
+    let mut gdb_cmd = Command::new(&gdb_path);
+    gdb_cmd.arg(&t_path);
+    gdb_cmd.arg("-x");
+    gdb_cmd.arg(&script_path);
+    let script = gdb_script(desc, &openocd.host, openocd.ports.gdb);
 
-    let uploader_final_path = uploader_final_path.unwrap();
-    print!("Validating uploader path... ");
-    if !uploader_final_path.join("mik32_upload.py").exists() {
-        println!("ERROR!!!\n");
-        eprintln!("Could not find mik32-uploader.py in provided path. Make sure to clone mik32_uploader correctly...");
-        return Err(RunError::UploadFailed);
+    if desc.dry_run {
+        events::dry_run("gdb", &process::describe(&gdb_cmd));
+        events::dry_run(&format!("gdb script ({})", script_path.display()), &script);
+        return Ok(());
     }
-    println!("OK\n");
-    println!("Successfuly fetched mik32 uploader path!");
 
-    println!("Fetching openocd path...");
+    debug!("GDB script:\n{}", script);
+    fs::write(&script_path, &script).map_err(|e| RunError::io(&script_path, e))?;
+    info!("Performing attach to GDB executable provided...");
+    events::emit(Event::GdbStarted { gdb: events::path(&gdb_path), elf: events::path(&t_path) });
+    debug!("Running {}", process::describe(&gdb_cmd));
+    // gdb reads the terminal, its output passes through us to go to the log as well.
+    // With JSON events on stdout the console is moved to stderr.
+    let child = Supervised::spawn_interactive(gdb_cmd.stdin(Stdio::inherit()).stdout(Stdio::piped()).stderr(Stdio::piped()));
 
-    let mut openocd_fetch_success = false;
-    if openocd_path.is_none() {
-        println!("Fetching openocd from env variable MIK32_OPENOCD_PATH...");
-        match env::var("MIK32_OPENOCD_PATH") {
-            Ok(path) => {
-                openocd_final_path = Some(
-                    PathBuf::from_str(&path).expect("Failed to make PathBuf out of environment variable")
-                );
-                openocd_fetch_success = true;
-            }
-            Err(e) => {
-                eprintln!("Fetchig from MIK32_OPENOCD_PATH environment variable failed. Consider setting it to desired path. {}", e);
+    let result = match child {
+        Ok(mut child) => {
+            let gdb_stderr = StderrTail::capture_console(&mut child, "gdb");
+            let status = child.wait();
+            let stderr = gdb_stderr.finish();
+            match status {
+                Ok(status) if status.success() => Ok(()),
+                Ok(status) => Err(RunError::GdbFailed { reason: format!("gdb exited with {}", status), stderr }),
+                Err(e) => Err(RunError::GdbFailed { reason: e.to_string(), stderr }),
             }
         }
+        Err(e) => Err(RunError::GdbFailed { reason: format!("failed to run gdb, {}", e), stderr: String::new() }),
+    };
+    let _ = fs::remove_file(&script_path);
+    openocd.close();
+    result
+}
+
+pub fn run_wrapper(mut desc: FlashCmdDescriptor) -> Result<(), RunError>{
+
+    if !desc.project_dir.join("Cargo.toml").exists() {
+        return Err(RunError::NotAProject(desc.project_dir));
+    }
+
+    ProjectConfig::load(&desc.project_dir)
+        .and_then(|config| config.apply(&mut desc))
+        .map_err(RunError::BadConfig)?;
+
+    if desc.reuse && (desc.example.is_some() || desc.bin.is_some() || desc.package.is_some()) {
+        warn!("Unresolved arugents. Using 'reuse' will skip objcopy step completely. 'example', 'bin' and 'package' arguments here are useless because they aply themselves to objcopy.");
     }
-    if openocd_path.is_none() && !openocd_fetch_success {
-        println!("Fetching openocd path through which command...");
-        let which_cmd = Command::new("which")
-            .arg("openocd")
-            .output()
-            .expect("Failed to run openocd");
 
-        if !which_cmd.status.success() || which_cmd.stdout.is_empty() {
-            eprintln!("'which openocd' failed. This might be caused due to unpropper installation of openocd.");
-            return Err(RunError::UploadFailed);
+    let (app_hex_path, image, elf_path) = objcopy(&desc)?;
+    let Some(image) = image else {
+        // Dry run before the first build, there is nothing to check addresses against.
+        desc.boot_mode = match desc.boot_mode {
+            Some(BootMode::Undefined) | None => {
+                warn!("No image built yet, boot mode cannot be inferred. Assuming eeprom, pass --boot-mode to change.");
+                Some(BootMode::Eeprom)
+            }
+            mode => mode,
+        };
+        desc.app_hex_path = Some(app_hex_path);
+        if desc.gdb_target_path.is_none() {
+            desc.gdb_target_path = elf_path;
         }
-        openocd_final_path = Some(
-            PathBuf::from_str(
-                &String::from_utf8(which_cmd.stdout)
-                    .expect("Failed to make utf-8 string out of stdout of 'which openocd'")
-            ).expect("Failed to make PathBuf out of stdout string")
-        );
+        return upload_and_debug(desc);
+    };
+    desc.boot_mode = Some(resolve_boot_mode(desc.boot_mode.as_ref(), &image, chip(desc.mcu_type.as_ref()))?);
+    events::emit(Event::Image {
+        path: events::path(&app_hex_path),
+        size: image.size(),
+        segments: image.segments
+            .iter()
+            .map(|s| events::Segment { address: s.address, size: s.data.len() })
+            .collect(),
+        boot_mode: desc.boot_mode.as_ref().map(boot_mode_name),
+    });
+    desc.app_hex_path = Some(app_hex_path);
+    if desc.gdb_target_path.is_none() {
+        desc.gdb_target_path = elf_path;
     }
+    upload_and_debug(desc)
+}
 
-    if openocd_path.is_some() {
-        openocd_final_path = openocd_path.clone();
+/// Puts the image onto the board according to the resolved boot mode, then starts gdb if requested.
+fn upload_and_debug(desc: FlashCmdDescriptor) -> Result<(), RunError> {
+    // Upload events describe what happened to the board, a dry run reports its own steps instead.
+    let emit = |event: Event| if !desc.dry_run { events::emit(event) };
+    // Held until gdb exits, the board stays ours between upload and debugging.
+    let _lock = ProbeLock::acquire(&desc)?;
+    if matches!(desc.boot_mode, Some(BootMode::Ram)) {
+        // RAM images never go through upload, flash memories stay untouched.
+        // With gdb the image is loaded by gdb itself, otherwise it is loaded and started over openocd.
+        if !debug_requested(&desc) {
+            emit(Event::UploadStarted { backend: "native", boot_mode: Some("ram") });
+            run_in_ram(&desc)?;
+            emit(Event::UploadFinished);
+            return Ok(());
+        }
+    } else {
+        let flasher = select_flasher(desc.backend.as_ref(), desc.boot_mode.as_ref());
+        info!("Using {} upload backend", flasher.name());
+        emit(Event::UploadStarted { backend: flasher.name(), boot_mode: desc.boot_mode.as_ref().map(boot_mode_name) });
+        flasher.flash(&desc)?;
+        emit(Event::UploadFinished);
     }
-    
-    if openocd_final_path.is_none() {
-        eprintln!("Failed to fetch openocd path due to unexpected error...");
-        return Err(RunError::UploadFailed);
+    connect_gdb(&desc)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ihex::Segment;
+
+    fn image(segments: &[(u32, usize)]) -> HexImage {
+        let segments = segments.iter().map(|&(address, len)| Segment { address, data: vec![0x13; len] }).collect();
+        HexImage::from_segments(segments, None).unwrap()
     }
-    let openocd_final_path = openocd_final_path.unwrap();
 
-    print!("Validating openocd... ");
-    if !command_exists(openocd_final_path.to_str().unwrap()) {
-        println!("ERROR");
-        eprintln!("Seems that openocd command isn't working or doesn't exist at all, please make sure to install openocd correctly.");
-        return Err(RunError::UploadFailed);
+    fn resolved(boot_mode: Option<BootMode>, image: &HexImage) -> Result<&'static str, String> {
+        resolve_boot_mode(boot_mode.as_ref(), image, chip(None))
+            .map(|mode| boot_mode_name(&mode))
+            .map_err(|e| e.to_string())
     }
 
-    println!("OK\n");
-    println!("Successfuly fetched openocd path. Preparing to upload...");
+    #[test]
+    fn boot_mode_is_inferred_from_every_segment() {
+        assert_eq!(resolved(None, &image(&[(0x0100_0000, 300), (0x0100_0400, 10)])), Ok("eeprom"));
+        assert_eq!(resolved(Some(BootMode::Undefined), &image(&[(0x0200_0000, 64)])), Ok("ram"));
+        assert_eq!(resolved(None, &image(&[(0x8000_0000, 4096), (0x8010_0000, 16)])), Ok("spifi"));
+    }
 
-    
-    if app_hex_path.is_none() {
-        eprintln!("Unexpected error during upload preparation...");
-        return Err(RunError::UploadFailed);
+    #[test]
+    fn image_in_two_memories_is_not_inferred() {
+        let error = resolved(None, &image(&[(0x0100_0000, 128), (0x8000_0000, 128)])).unwrap_err();
+        assert!(error.contains("both EEPROM and SPIFI"), "{}", error);
     }
-    
-    if !command_exists("Python3") {
-        eprintln!("Python3 command not found. Make sure to properly install it.");
-        return Err(RunError::PackageNotInstalled);
+
+    #[test]
+    fn image_outside_known_memories_is_rejected() {
+        let error = resolved(None, &image(&[(0x0100_0000, 128), (0x4000_0000, 4)])).unwrap_err();
+        assert!(error.contains("0x40000000..0x40000004 is neither"), "{}", error);
     }
 
-    println!("Uploading...");
-    let mut upload_cmd = Command::new("python3");
-    upload_cmd.arg(absolute(uploader_final_path.join("mik32_upload.py")).unwrap().to_str().unwrap());
-    upload_cmd.arg("--run-openocd");
-    upload_cmd.arg("--openocd-exec");
-    upload_cmd.arg(absolute(openocd_final_path).unwrap().to_str().unwrap());
-    upload_cmd.arg("--openocd-scripts");
-    upload_cmd.arg(absolute(uploader_final_path.join("openocd-scripts")).unwrap().to_str().unwrap());
-    upload_cmd.arg(absolute(app_hex_path.unwrap()).unwrap().to_str().unwrap());
-    
-    if use_quad_spi {
-        upload_cmd.arg("--use-quad-spi");
+    #[test]
+    fn inferred_mode_still_checks_region_end() {
+        let error = resolved(None, &image(&[(0x0100_1f00, 512)])).unwrap_err();
+        assert!(error.contains("does not fit EEPROM"), "{}", error);
     }
 
-    if openocd_host.is_some() {
-        upload_cmd.args([
-            "--openocd-host",
-            &openocd_host.unwrap(),
-        ]);
-        
+    #[test]
+    fn mismatched_boot_mode_is_rejected() {
+        let eeprom = image(&[(0x0100_0000, 128)]);
+        let error = resolved(Some(BootMode::Spifi), &eeprom).unwrap_err();
+        assert!(error.contains("0x01000000..0x01000080 does not fit SPIFI"), "{}", error);
+        assert!(resolved(Some(BootMode::Ram), &eeprom).is_err());
+        assert_eq!(resolved(Some(BootMode::Eeprom), &eeprom), Ok("eeprom"));
     }
 
-    if openocd_port.is_some() {
-        upload_cmd.args([
-            "--openocd-port", 
-            &openocd_port.unwrap()
-        ]);
+    /// `compiler-artifact` line as printed by `cargo build --message-format=json-render-diagnostics`.
+    fn artifact_line(kind: &str, name: &str, fresh: bool) -> String {
+        let dir = "/work/blink/target/riscv32imc-unknown-none-elf/release";
+        let (crate_type, filenames, executable) = match kind {
+            "lib" => ("lib", format!(r#"["{dir}/libblink.rlib","{dir}/libblink.rmeta"]"#), "null".to_owned()),
+            "custom-build" => ("bin", format!(r#"["{dir}/build/blink-1f2e3d/build-script-build"]"#), "null".to_owned()),
+            "example" => ("bin", format!(r#"["{dir}/examples/{name}"]"#), format!(r#""{dir}/examples/{name}""#)),
+            _ => ("bin", format!(r#"["{dir}/{name}"]"#), format!(r#""{dir}/{name}""#)),
+        };
+        format!(
+            r#"{{"reason":"compiler-artifact","package_id":"path+file:///work/blink#0.1.0","manifest_path":"/work/blink/Cargo.toml","target":{{"kind":["{kind}"],"crate_types":["{crate_type}"],"name":"{name}","src_path":"/work/blink/src/{name}.rs","edition":"2024","doc":true,"doctest":false,"test":true}},"profile":{{"opt_level":"s","debuginfo":2,"debug_assertions":false,"overflow_checks":false,"test":false}},"features":[],"filenames":{filenames},"executable":{executable},"fresh":{fresh}}}"#
+        )
     }
 
-    if adapter_speed.is_some() {
-        upload_cmd.args([
-            "--adapter-speed",
-            &adapter_speed.unwrap()
-        ]);
+    fn messages(artifacts: &[(&str, &str, bool)]) -> String {
+        let mut lines = vec![artifact_line("custom-build", "build-script-build", true)];
+        lines.push(r#"{"reason":"build-script-executed","package_id":"path+file:///work/blink#0.1.0","linked_libs":[],"linked_paths":[],"cfgs":[],"env":[],"out_dir":"/work/blink/target/release/build/blink-0a9b8c/out"}"#.to_owned());
+        lines.extend(artifacts.iter().map(|&(kind, name, fresh)| artifact_line(kind, name, fresh)));
+        lines.push(r#"{"reason":"build-finished","success":true}"#.to_owned());
+        lines.join("\n")
     }
 
-    if openocd_scripts.is_some() {
-        upload_cmd.args([
-            "--openocd-scripts",
-            absolute(openocd_scripts.unwrap()).unwrap().to_str().unwrap()
-        ]);
+    fn selected(messages: &str, desc: &FlashCmdDescriptor) -> Result<(String, PathBuf), String> {
+        select_artifact(messages, desc).map(|a| (a.name, a.executable)).map_err(|e| e.to_string())
     }
 
-    if openocd_interface.is_some() {
-        upload_cmd.args([
-            "--openocd-interface",
-            openocd_interface.unwrap().to_str().unwrap()
-        ]);
+    const RELEASE: &str = "/work/blink/target/riscv32imc-unknown-none-elf/release";
+
+    #[test]
+    fn only_binary_is_selected_among_libs_and_build_scripts() {
+        let messages = messages(&[("lib", "blink", false), ("bin", "blink", false)]);
+        let (name, executable) = selected(&messages, &FlashCmdDescriptor::default()).unwrap();
+        assert_eq!(name, "blink");
+        assert_eq!(executable, Path::new(RELEASE).join("blink"));
     }
 
-    if openocd_target.is_some() {
-        upload_cmd.args([
-            "--openocd-target",
-            openocd_target.unwrap().to_str().unwrap()
-        ]);
+    #[test]
+    fn fresh_artifact_is_selected() {
+        let messages = messages(&[("lib", "blink", true), ("bin", "blink", true)]);
+        assert_eq!(selected(&messages, &FlashCmdDescriptor::default()).unwrap().0, "blink");
     }
 
-    println!("Uploading binary...");
+    #[test]
+    fn bin_and_example_pick_their_target() {
+        let messages = messages(&[("bin", "blink", true), ("bin", "uart", false), ("example", "blink", false)]);
+        let desc = FlashCmdDescriptor { bin: Some("uart".to_owned()), ..Default::default() };
+        assert_eq!(selected(&messages, &desc).unwrap().1, Path::new(RELEASE).join("uart"));
 
-    let upload_status = upload_cmd.status().expect("Failed to run upload");
-    if !upload_status.success() {
-        eprintln!("Failed to upload application");
-        return Err(RunError::UploadFailed);
+        let desc = FlashCmdDescriptor { example: Some("blink".to_owned()), ..Default::default() };
+        assert_eq!(selected(&messages, &desc).unwrap().1, Path::new(RELEASE).join("examples/blink"));
     }
-    
-    println!("Aplication uploaded successfully");
-    Ok(())
-}
 
-fn connect_gdb(
-    gdb_exec: Option<String>, 
-    gdb_target_path: Option<PathBuf>, 
-    openocd_interface: Option<PathBuf>,
-    openocd_scripts: Option<PathBuf>,
-    openocd_target: Option<PathBuf>,
-    
-) -> Result<(), RunError>{
-    if gdb_exec.is_none() {
-        eprintln!("gdb executable was not provided. Skipping this step.");
-        return Err(RunError::NoGdbExec);
-    }
-    let t_path= gdb_target_path.unwrap();
-    let o_int_path = openocd_interface.unwrap();
-    let o_scr_path = openocd_scripts.unwrap();
-    let o_tar_path = openocd_target.unwrap();
-
-    let mut openocd = Command::new("openocd");
-    openocd.arg("-f").arg(o_scr_path.join(o_int_path));
-    openocd.arg("-f").arg(o_scr_path.join(o_tar_path));
-
-    let _ = openocd.stdout(Stdio::inherit())
-            .stderr(Stdio::inherit())
-            .spawn()
-            .expect("Failed to run openocd to connect GDB");
-
-    sleep(Duration::from_millis(500));
-
-    println!("Performing attach to GDB executable provided...");
-    let mut gdb_cmd = Command::new(gdb_exec.unwrap());
-    gdb_cmd.arg(
-        absolute(
-            t_path
-        ).unwrap()
-    );
-    gdb_cmd.arg("-x");
-    gdb_cmd.arg("-");
+    #[test]
+    fn several_executables_need_a_selection() {
+        let messages = messages(&[("bin", "blink", false), ("bin", "uart", false)]);
+        let error = selected(&messages, &FlashCmdDescriptor::default()).unwrap_err();
+        assert!(error.contains("several executables (blink, uart)"), "{}", error);
+    }
 
-    let child = gdb_cmd
-        .stdin(Stdio::piped())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn();
+    #[test]
+    fn missing_executable_is_reported() {
+        let messages = messages(&[("lib", "blink", false), ("bin", "blink", false)]);
+        let desc = FlashCmdDescriptor { example: Some("blink".to_owned()), ..Default::default() };
+        assert!(selected(&messages, &desc).unwrap_err().contains("did not report any example executable"));
 
-    match child {
-        Ok(mut child) => {
-            use std::io::Write;
-            let mut stdin = child.stdin.take().unwrap();
-            stdin.write_all(
-                    r#"
-                set mem inaccessible-by-default off
-                mem 0x01000000 0x01002000 ro
-                mem 0x80000000 0xffffffff ro
-                set arch riscv:rv32
-                set remotetimeout 10
-                set remote hardware-breakpoint-limit 2
-                target remote localhost:3333
-                load
-                "#.as_bytes()).unwrap();
-            stdin.
-            Ok(())
-        }
-        Err(e) => {
-            eprintln!("Failed to run gdb due to unexpected error, {}", e);
-            Err(RunError::GdbFailed)
-        }
+        let desc = FlashCmdDescriptor { bin: Some("uart".to_owned()), ..Default::default() };
+        assert!(selected(&messages, &desc).is_err());
     }
-}
 
-pub fn run_wrapper(mut desc: FlashCmdDescriptor) -> Result<(), RunError>{
+    fn planned(desc: FlashCmdDescriptor) -> Result<(String, PathBuf), String> {
+        let desc = FlashCmdDescriptor { target_dir: Some(PathBuf::from("/work/target")), ..desc };
+        planned_artifact(&desc).map(|a| (a.name, a.executable)).map_err(|e| e.to_string())
+    }
 
-    if !desc.project_dir.join("Cargo.toml").exists() {
-        eprintln!("Not a project directory. Exiting...");
-        return Err(RunError::NotAProject);
-    }
-
-    if desc.reuse && desc.example.is_some() {
-        eprintln!("Unresolved arugents. Using 'reuse' will skip objcopy step completely. 'example' argument here is useless because it aplies itself to objcopy.");
-    }
-
-    objcopy(
-        &mut desc.app_hex_path,
-        desc.reuse,
-        desc.example,
-        &desc.project_dir
-    )?;
-    upload(
-        desc.openocd_path,
-        desc.uploader_path,
-        desc.app_hex_path.clone(),
-        desc.use_quad_spi,
-        desc.openocd_host,
-        desc.openocd_port,
-        desc.adapter_speed,
-        desc.openocd_scripts.clone(),
-        desc.openocd_interface.clone(),
-        desc.openocd_target.clone(),
-        &desc.project_dir
-    )?;
-    connect_gdb(
-        desc.gdb_exec, 
-        desc.gdb_target_path, 
-        desc.openocd_interface,
-        desc.openocd_scripts,
-        desc.openocd_target,
-    )?;
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn planned_artifact_follows_cargo_layout() {
+        let target = Path::new("/work/target").join(RUST_TARGET);
+        let bin = planned(FlashCmdDescriptor { bin: Some("blink".to_owned()), ..Default::default() });
+        assert_eq!(bin, Ok(("blink".to_owned(), target.join("release/blink"))));
+
+        let example = planned(FlashCmdDescriptor {
+            example: Some("uart".to_owned()),
+            bin: Some("blink".to_owned()),
+            profile: Some("dev".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(example, Ok(("uart".to_owned(), target.join("debug/examples/uart"))));
+
+        let custom = planned(FlashCmdDescriptor {
+            package: Some("firmware".to_owned()),
+            profile: Some("flash".to_owned()),
+            ..Default::default()
+        });
+        assert_eq!(custom, Ok(("firmware".to_owned(), target.join("flash/firmware"))));
+    }
+
+    #[test]
+    fn planned_artifact_needs_a_name() {
+        let desc = FlashCmdDescriptor { project_dir: PathBuf::from("/nonexistent"), ..Default::default() };
+        assert!(planned(desc).unwrap_err().contains("pass --bin or --package"));
+    }
+}
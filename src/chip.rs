@@ -0,0 +1,122 @@
+use crate::{BootMode, MCUType};
+
+/// Rust target every MIK32 variant is built for.
+pub const RUST_TARGET: &str = "riscv32imc-unknown-none-elf";
+
+/// Address window of one of the memories the image can be placed into.
+pub struct MemoryRegion {
+    pub name: &'static str,
+    pub start: u32,
+    pub size: u32,
+    /// Smallest programmable unit. Zero for memories written directly, like RAM.
+    pub page_size: u32,
+    /// Smallest erasable unit. Zero for memories that need no erase.
+    pub erase_size: u32,
+    pub read_only: bool,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> u32 {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        (self.start..self.end()).contains(&address)
+    }
+}
+
+pub struct Chip {
+    pub name: &'static str,
+    pub eeprom: MemoryRegion,
+    pub ram: MemoryRegion,
+    pub spifi: MemoryRegion,
+    /// Target config relative to openocd 'scripts' directory.
+    pub openocd_target: &'static str,
+}
+
+impl Chip {
+    pub fn regions(&self) -> [&MemoryRegion; 3] {
+        [&self.eeprom, &self.ram, &self.spifi]
+    }
+
+    /// Memory the application runs from in the given boot mode.
+    pub fn boot_region(&self, boot_mode: &BootMode) -> Option<&MemoryRegion> {
+        match boot_mode {
+            BootMode::Eeprom => Some(&self.eeprom),
+            BootMode::Ram => Some(&self.ram),
+            BootMode::Spifi => Some(&self.spifi),
+            BootMode::Undefined => None,
+        }
+    }
+
+    /// Boot mode whose memory contains `address`.
+    pub fn boot_mode_of(&self, address: u32) -> Option<BootMode> {
+        [BootMode::Eeprom, BootMode::Ram, BootMode::Spifi]
+            .into_iter()
+            .find(|mode| self.boot_region(mode).is_some_and(|region| region.contains(address)))
+    }
+
+    /// GDB memory map, so gdb uses hardware breakpoints in read-only memories and does not try to write them.
+    pub fn gdb_memory_map(&self) -> String {
+        let mut map = String::from("set mem inaccessible-by-default off\n");
+        for region in self.regions().iter().filter(|r| r.read_only) {
+            map.push_str(&format!("mem 0x{:08x} 0x{:08x} ro\n", region.start, region.end()));
+        }
+        map
+    }
+}
+
+const EEPROM: MemoryRegion = MemoryRegion {
+    name: "EEPROM",
+    start: 0x0100_0000,
+    size: 8 * 1024,
+    page_size: 128,
+    erase_size: 128,
+    read_only: true,
+};
+
+const RAM: MemoryRegion = MemoryRegion {
+    name: "RAM",
+    start: 0x0200_0000,
+    size: 16 * 1024,
+    page_size: 0,
+    erase_size: 0,
+    read_only: false,
+};
+
+/// Memory-mapped window of external SPIFI flash. Actual chip capacity is read from its JEDEC ID.
+const SPIFI: MemoryRegion = MemoryRegion {
+    name: "SPIFI",
+    start: 0x8000_0000,
+    size: 0x1000_0000,
+    page_size: 256,
+    erase_size: 4096,
+    read_only: true,
+};
+
+/// External flash size `init` writes to memory.x when none is given: 8 MiB, as on common MIK32 boards.
+pub const DEFAULT_SPIFI_FLASH_KIB: u32 = 8 * 1024;
+
+static MIK32V0: Chip = Chip {
+    name: "MIK32 Amur (V0)",
+    eeprom: EEPROM,
+    ram: RAM,
+    spifi: SPIFI,
+    openocd_target: "target/mik32.cfg",
+};
+
+static MIK32V2: Chip = Chip {
+    name: "MIK32 Amur (V2)",
+    eeprom: EEPROM,
+    ram: RAM,
+    spifi: SPIFI,
+    openocd_target: "target/mik32.cfg",
+};
+
+/// Chip description for --mcu-type. MIK32V2 is assumed when nothing is selected.
+pub fn chip(mcu_type: Option<&MCUType>) -> &'static Chip {
+    match mcu_type {
+        Some(MCUType::MIK32V0) => &MIK32V0,
+        Some(MCUType::MIK32V2) | None => &MIK32V2,
+    }
+}
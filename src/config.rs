@@ -0,0 +1,560 @@
+use std::{collections::{BTreeMap, HashMap}, env, fs, path::{Path, PathBuf}};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::{exit_code, tools::{self, ResolvedTool, Tool, ToolError, ToolSource}, Backend, BootMode, FlashCmdDescriptor, MCUType, ObjcopyTool};
+
+pub const LOCAL_CONFIG_FILE: &str = ".mik32.local.toml";
+
+/// Keys accepted in `[package.metadata.mik32]` and `.mik32.local.toml`, each with the environment variable overriding it.
+/// Command line flags of `run` override all of them. Tool paths have no variable here: `tools::resolve`
+/// falls back to MIK32_UPLOADER_PATH, MIK32_OPENOCD_PATH and MIK32_GDB_EXEC after the project config.
+const KEYS: &[(&str, Option<&str>)] = &[
+    ("probe", Some("MIK32_PROBE")),
+    ("probe-serial", Some("MIK32_PROBE_SERIAL")),
+    ("uploader-path", None),
+    ("openocd-path", None),
+    ("openocd-scripts", Some("MIK32_OPENOCD_SCRIPTS")),
+    ("openocd-interface", Some("MIK32_OPENOCD_INTERFACE")),
+    ("openocd-target", Some("MIK32_OPENOCD_TARGET")),
+    ("openocd-host", Some("MIK32_OPENOCD_HOST")),
+    ("openocd-port", Some("MIK32_OPENOCD_PORT")),
+    ("gdb-port", Some("MIK32_GDB_PORT")),
+    ("adapter-speed", Some("MIK32_ADAPTER_SPEED")),
+    ("gdb", Some("MIK32_GDB")),
+    ("gdb-exec", None),
+    ("backend", Some("MIK32_BACKEND")),
+    ("boot-mode", Some("MIK32_BOOT_MODE")),
+    ("mcu-type", Some("MIK32_MCU_TYPE")),
+    ("objcopy", Some("MIK32_OBJCOPY")),
+    ("profile", Some("MIK32_PROFILE")),
+    ("use-quad-spi", Some("MIK32_USE_QUAD_SPI")),
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    BadValue { key: &'static str, value: String, source: Source },
+    UnknownProbe(String, Option<PathBuf>),
+}
+
+impl ConfigError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ConfigError::Io(..) => exit_code::IO,
+            _ => exit_code::USAGE,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "failed to parse {}: {}", path.display(), e),
+            ConfigError::BadValue { key, value, source } => {
+                write!(f, "invalid value '{}' for '{}' from {}", value, key, source)
+            }
+            ConfigError::UnknownProbe(name, Some(path)) => write!(f, "probe '{}' is not defined in {}", name, path.display()),
+            ConfigError::UnknownProbe(name, None) => write!(f, "probe '{}' requested, but user config directory is unknown", name),
+        }
+    }
+}
+
+/// Layer a value was taken from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    Metadata,
+    Local,
+    Env(&'static str),
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Metadata => write!(f, "Cargo.toml [package.metadata.mik32]"),
+            Source::Local => write!(f, "{}", LOCAL_CONFIG_FILE),
+            Source::Env(var) => write!(f, "environment {}", var),
+        }
+    }
+}
+
+impl Source {
+    fn layer(&self) -> Layer {
+        match self {
+            Source::Metadata | Source::Local => Layer::Files,
+            Source::Env(_) => Layer::Env,
+        }
+    }
+}
+
+/// Settings applied in one pass of `ProjectConfig::apply`.
+#[derive(Clone, Copy, PartialEq)]
+enum Layer {
+    Files,
+    Env,
+}
+
+pub struct Setting {
+    pub value: String,
+    pub source: Source,
+}
+
+/// Project defaults for `run`: `[package.metadata.mik32]` of Cargo.toml, overridden by `.mik32.local.toml`,
+/// overridden by environment variables. Selected probe profile sits between the files and the environment.
+pub struct ProjectConfig {
+    settings: HashMap<&'static str, Setting>,
+}
+
+impl ProjectConfig {
+    pub fn load(project_dir: &Path) -> Result<Self, ConfigError> {
+        Self::load_with(project_dir, |var| env::var(var).ok())
+    }
+
+    /// `load` with environment variables read through `var`.
+    fn load_with(project_dir: &Path, var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut settings = HashMap::new();
+
+        let manifest_path = project_dir.join("Cargo.toml");
+        let manifest = read_table(&manifest_path)?;
+        if let Some(metadata) = manifest
+            .get("package")
+            .and_then(|p| p.get("metadata"))
+            .and_then(|m| m.get("mik32"))
+        {
+            let Some(metadata) = metadata.as_table() else {
+                return Err(ConfigError::Parse(manifest_path, "[package.metadata.mik32] must be a table".to_owned()));
+            };
+            merge(&mut settings, metadata, Source::Metadata)?;
+        }
+
+        let local_path = project_dir.join(LOCAL_CONFIG_FILE);
+        if local_path.exists() {
+            merge(&mut settings, &read_table(&local_path)?, Source::Local)?;
+        }
+
+        for (key, name) in KEYS {
+            if let Some(name) = name
+                && let Some(value) = var(name)
+                && !value.is_empty()
+            {
+                settings.insert(*key, Setting { value, source: Source::Env(name) });
+            }
+        }
+        Ok(ProjectConfig { settings })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Setting> {
+        self.settings.get(key)
+    }
+
+    /// Fills every option that was not given on the command line.
+    /// Precedence is command line, environment, selected probe profile, project config files, built-in defaults.
+    pub fn apply(&self, desc: &mut FlashCmdDescriptor) -> Result<(), ConfigError> {
+        self.fill(&mut desc.probe, "probe", Layer::Env, |v| Some(v.to_owned()))?;
+        self.fill(&mut desc.probe, "probe", Layer::Files, |v| Some(v.to_owned()))?;
+        let Some(probe) = desc.probe.clone() else {
+            return self.apply_layers(desc, None);
+        };
+        let user_config = UserConfig::load()?;
+        let Some(profile) = user_config.probes.get(&probe) else {
+            return Err(ConfigError::UnknownProbe(probe, user_config_path()));
+        };
+        self.apply_layers(desc, Some(profile))
+    }
+
+    fn apply_layers(&self, desc: &mut FlashCmdDescriptor, profile: Option<&ProbeProfile>) -> Result<(), ConfigError> {
+        self.apply_layer(desc, Layer::Env)?;
+        if let Some(profile) = profile {
+            profile.apply(desc);
+        }
+        self.apply_layer(desc, Layer::Files)
+    }
+
+    fn apply_layer(&self, desc: &mut FlashCmdDescriptor, layer: Layer) -> Result<(), ConfigError> {
+        self.fill(&mut desc.probe_serial, "probe-serial", layer, |v| Some(v.to_owned()))?;
+        self.fill(&mut desc.uploader_path, "uploader-path", layer, |v| Some(PathBuf::from(v)))?;
+        self.fill(&mut desc.openocd_path, "openocd-path", layer, |v| Some(PathBuf::from(v)))?;
+        self.fill(&mut desc.openocd_scripts, "openocd-scripts", layer, |v| Some(PathBuf::from(v)))?;
+        self.fill(&mut desc.openocd_interface, "openocd-interface", layer, |v| Some(PathBuf::from(v)))?;
+        self.fill(&mut desc.openocd_target, "openocd-target", layer, |v| Some(PathBuf::from(v)))?;
+        self.fill(&mut desc.openocd_host, "openocd-host", layer, |v| Some(v.to_owned()))?;
+        self.fill(&mut desc.openocd_port, "openocd-port", layer, |v| v.parse::<u16>().ok().map(|_| v.to_owned()))?;
+        self.fill(&mut desc.gdb_port, "gdb-port", layer, |v| v.parse::<u16>().ok().map(|_| v.to_owned()))?;
+        self.fill(&mut desc.adapter_speed, "adapter-speed", layer, |v| v.parse::<u32>().ok().map(|_| v.to_owned()))?;
+        self.fill(&mut desc.gdb_exec, "gdb-exec", layer, |v| Some(v.to_owned()))?;
+        self.fill(&mut desc.backend, "backend", layer, |v| <Backend as ValueEnum>::from_str(v, true).ok())?;
+        self.fill(&mut desc.boot_mode, "boot-mode", layer, |v| <BootMode as ValueEnum>::from_str(v, true).ok())?;
+        self.fill(&mut desc.mcu_type, "mcu-type", layer, |v| <MCUType as ValueEnum>::from_str(v, true).ok())?;
+        self.fill(&mut desc.objcopy, "objcopy", layer, |v| <ObjcopyTool as ValueEnum>::from_str(v, true).ok())?;
+        self.fill(&mut desc.profile, "profile", layer, |v| Some(v.to_owned()))?;
+        if !desc.gdb {
+            let mut gdb = None;
+            self.fill(&mut gdb, "gdb", layer, |v| v.parse::<bool>().ok())?;
+            desc.gdb = gdb.unwrap_or(false);
+        }
+        if !desc.use_quad_spi {
+            let mut use_quad_spi = None;
+            self.fill(&mut use_quad_spi, "use-quad-spi", layer, |v| v.parse::<bool>().ok())?;
+            desc.use_quad_spi = use_quad_spi.unwrap_or(false);
+        }
+        Ok(())
+    }
+
+    fn fill<T>(
+        &self,
+        slot: &mut Option<T>,
+        key: &'static str,
+        layer: Layer,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> Result<(), ConfigError> {
+        if slot.is_some() {
+            return Ok(());
+        }
+        if let Some(setting) = self.settings.get(key).filter(|setting| setting.source.layer() == layer) {
+            let value = parse(&setting.value).ok_or_else(|| ConfigError::BadValue {
+                key,
+                value: setting.value.clone(),
+                source: setting.source.clone(),
+            })?;
+            *slot = Some(value);
+        }
+        Ok(())
+    }
+}
+
+/// `cargo mik32 config show`: every key with its resolved value and the layer it came from.
+pub fn show(project_dir: &Path) -> Result<(), ConfigError> {
+    let config = ProjectConfig::load(project_dir)?;
+    for (key, shown) in rows(&config, |tool| tools::resolve(tool, None, project_dir)) {
+        match shown {
+            Some((value, source)) => println!("{:<18} = {:<32} ({})", key, value, source),
+            None => println!("{:<18}   (not set)", key),
+        }
+    }
+    println!("\nCommand line flags of 'run' override all of the above. Probe profile overrides the config files, but not the environment.");
+
+    match user_config_path() {
+        Some(path) => {
+            let user_config = UserConfig::load()?;
+            if user_config.probes.is_empty() {
+                println!("No probe profiles in {}", path.display());
+            } else {
+                println!("Probe profiles in {}:", path.display());
+                for (name, profile) in &user_config.probes {
+                    println!("  {:<16} {}", name, profile);
+                }
+            }
+        }
+        None => println!("User config directory is unknown, probe profiles are unavailable."),
+    }
+    Ok(())
+}
+
+/// Value and source of every key for `show`. Tool paths missing from the config are looked up
+/// the way `run` does: environment variable, project directory, PATH.
+fn rows(
+    config: &ProjectConfig,
+    resolve: impl Fn(Tool) -> Result<ResolvedTool, ToolError>,
+) -> Vec<(&'static str, Option<(String, String)>)> {
+    KEYS.iter()
+        .map(|(key, _)| {
+            let shown = match (config.get(key), tool_key(key)) {
+                (Some(setting), _) => Some((setting.value.clone(), setting.source.to_string())),
+                (None, Some(tool)) => resolve(tool).ok().map(|resolved| {
+                    let source = match resolved.source {
+                        ToolSource::Env(var) => Source::Env(var).to_string(),
+                        source => source.to_string(),
+                    };
+                    (resolved.path.display().to_string(), source)
+                }),
+                (None, None) => None,
+            };
+            (*key, shown)
+        })
+        .collect()
+}
+
+/// Tool located by a path key.
+fn tool_key(key: &str) -> Option<Tool> {
+    match key {
+        "uploader-path" => Some(Tool::Uploader),
+        "openocd-path" => Some(Tool::Openocd),
+        "gdb-exec" => Some(Tool::Gdb),
+        _ => None,
+    }
+}
+
+/// `~/.config/cargo-mik32/config.toml`, honoring XDG_CONFIG_HOME, or `%APPDATA%\cargo-mik32\config.toml` on Windows.
+pub fn user_config_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| if cfg!(windows) { env::var_os("APPDATA").map(PathBuf::from) } else { None })
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("cargo-mik32").join("config.toml"))
+}
+
+/// Directory for per-user runtime state, like the background openocd pidfile: `$XDG_RUNTIME_DIR/cargo-mik32`,
+/// or a per-user directory in the system temp dir. Created on first use.
+pub fn runtime_dir() -> std::io::Result<PathBuf> {
+    let dir = match env::var_os("XDG_RUNTIME_DIR").filter(|v| !v.is_empty()) {
+        Some(base) => PathBuf::from(base).join("cargo-mik32"),
+        None => {
+            let user = env::var("USER").or_else(|_| env::var("USERNAME")).unwrap_or_default();
+            env::temp_dir().join(format!("cargo-mik32-{}", user))
+        }
+    };
+    fs::create_dir_all(&dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(dir)
+}
+
+/// User-global settings shared by all projects.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    #[serde(default)]
+    pub probes: BTreeMap<String, ProbeProfile>,
+}
+
+/// Named debug probe setup, defined as `[probes.<name>]`.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ProbeProfile {
+    pub interface: Option<PathBuf>,
+    pub adapter_speed: Option<u32>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub gdb_port: Option<u16>,
+    pub serial: Option<String>,
+}
+
+impl UserConfig {
+    /// Missing file is an empty config.
+    pub fn load() -> Result<Self, ConfigError> {
+        let Some(path) = user_config_path().filter(|path| path.exists()) else {
+            return Ok(UserConfig::default());
+        };
+        let text = fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path, e.message().to_owned()))
+    }
+}
+
+impl ProbeProfile {
+    fn apply(&self, desc: &mut FlashCmdDescriptor) {
+        desc.openocd_interface = desc.openocd_interface.take().or(self.interface.clone());
+        desc.adapter_speed = desc.adapter_speed.take().or(self.adapter_speed.map(|s| s.to_string()));
+        desc.openocd_host = desc.openocd_host.take().or(self.host.clone());
+        desc.openocd_port = desc.openocd_port.take().or(self.port.map(|p| p.to_string()));
+        desc.gdb_port = desc.gdb_port.take().or(self.gdb_port.map(|p| p.to_string()));
+        desc.probe_serial = desc.probe_serial.take().or(self.serial.clone());
+    }
+}
+
+impl std::fmt::Display for ProbeProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(interface) = &self.interface {
+            parts.push(format!("interface {}", interface.display()));
+        }
+        if let Some(speed) = self.adapter_speed {
+            parts.push(format!("{} kHz", speed));
+        }
+        if let Some(host) = &self.host {
+            parts.push(format!("host {}", host));
+        }
+        if let Some(port) = self.port {
+            parts.push(format!("port {}", port));
+        }
+        if let Some(port) = self.gdb_port {
+            parts.push(format!("gdb port {}", port));
+        }
+        if let Some(serial) = &self.serial {
+            parts.push(format!("serial {}", serial));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// `[package] name` of the project manifest, None for virtual workspaces or unreadable manifests.
+pub fn package_name(project_dir: &Path) -> Option<String> {
+    read_table(&project_dir.join("Cargo.toml"))
+        .ok()?
+        .get("package")?
+        .get("name")?
+        .as_str()
+        .map(str::to_owned)
+}
+
+fn read_table(path: &Path) -> Result<toml::Table, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+    text.parse::<toml::Table>().map_err(|e| ConfigError::Parse(path.to_owned(), e.message().to_owned()))
+}
+
+fn merge(settings: &mut HashMap<&'static str, Setting>, table: &toml::Table, source: Source) -> Result<(), ConfigError> {
+    for (name, value) in table {
+        let Some((key, _)) = KEYS.iter().find(|(key, _)| key == name) else {
+            log::warn!("unknown key '{}' in {}, ignoring", name, source);
+            continue;
+        };
+        let value = match value {
+            toml::Value::String(s) => s.clone(),
+            toml::Value::Integer(i) => i.to_string(),
+            toml::Value::Boolean(b) => b.to_string(),
+            other => return Err(ConfigError::BadValue { key, value: other.to_string(), source }),
+        };
+        settings.insert(*key, Setting { value, source: source.clone() });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(settings: &[(&'static str, &str, Source)]) -> ProjectConfig {
+        let settings = settings
+            .iter()
+            .map(|(key, value, source)| (*key, Setting { value: (*value).to_owned(), source: source.clone() }))
+            .collect();
+        ProjectConfig { settings }
+    }
+
+    /// Project directory with the given Cargo.toml and `.mik32.local.toml`, removed when dropped.
+    struct Project(PathBuf);
+
+    impl Project {
+        fn new(name: &str, manifest: &str, local: Option<&str>) -> Self {
+            let dir = env::temp_dir().join(format!("cargo-mik32-config-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("Cargo.toml"), manifest).unwrap();
+            if let Some(local) = local {
+                fs::write(dir.join(LOCAL_CONFIG_FILE), local).unwrap();
+            }
+            Project(dir)
+        }
+
+        fn load(&self, vars: &[(&str, &str)]) -> Result<ProjectConfig, ConfigError> {
+            ProjectConfig::load_with(&self.0, |name| {
+                vars.iter().find(|(var, _)| *var == name).map(|(_, value)| (*value).to_owned())
+            })
+        }
+    }
+
+    impl Drop for Project {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const MANIFEST: &str = r#"
+        [package]
+        name = "blink"
+
+        [package.metadata.mik32]
+        probe = "metadata"
+        gdb-port = 3334
+        adapter-speed = 500
+        use-quad-spi = true
+        openocd-path = "tools/openocd"
+    "#;
+
+    fn setting(config: &ProjectConfig, key: &str) -> (String, String) {
+        let setting = config.get(key).unwrap();
+        (setting.value.clone(), setting.source.to_string())
+    }
+
+    #[test]
+    fn local_file_overrides_metadata_and_env_overrides_both() {
+        let project = Project::new("layers", MANIFEST, Some("probe = \"local\"\ngdb-port = 3335\n"));
+        let config = project
+            .load(&[("MIK32_GDB_PORT", "3336"), ("MIK32_ADAPTER_SPEED", ""), ("MIK32_OPENOCD_PATH", "/env/openocd")])
+            .unwrap();
+
+        assert_eq!(setting(&config, "probe"), ("local".to_owned(), LOCAL_CONFIG_FILE.to_owned()));
+        assert_eq!(setting(&config, "gdb-port"), ("3336".to_owned(), "environment MIK32_GDB_PORT".to_owned()));
+        assert_eq!(setting(&config, "adapter-speed").1, "Cargo.toml [package.metadata.mik32]", "empty variable is ignored");
+        assert_eq!(setting(&config, "use-quad-spi").0, "true");
+        assert_eq!(setting(&config, "openocd-path").0, "tools/openocd", "tool variables are left to the tool resolver");
+        assert!(config.get("openocd-host").is_none());
+    }
+
+    #[test]
+    fn project_without_config_is_empty() {
+        let project = Project::new("empty", "[package]\nname = \"blink\"\n", None);
+        let config = project.load(&[]).unwrap();
+        assert!(KEYS.iter().all(|(key, _)| config.get(key).is_none()));
+    }
+
+    #[test]
+    fn malformed_config_is_rejected() {
+        let project = Project::new("bad-metadata", "[package.metadata]\nmik32 = 1\n", None);
+        assert!(matches!(project.load(&[]), Err(ConfigError::Parse(..))));
+
+        let project = Project::new("bad-value", "[package.metadata.mik32]\ngdb-port = [1]\n", None);
+        assert!(matches!(project.load(&[]), Err(ConfigError::BadValue { key: "gdb-port", .. })));
+    }
+
+    #[test]
+    fn show_reports_resolved_tool_paths() {
+        let config = config(&[("openocd-path", "tools/openocd", Source::Local)]);
+        let rows = rows(&config, |tool| match tool {
+            Tool::Uploader => Ok(ResolvedTool {
+                path: PathBuf::from("/env/mik32-uploader"),
+                source: ToolSource::Env("MIK32_UPLOADER_PATH"),
+                version: None,
+            }),
+            Tool::Gdb => Ok(ResolvedTool { path: PathBuf::from("/usr/bin/gdb-multiarch"), source: ToolSource::Path, version: None }),
+            tool => panic!("{} is set in the config", tool.name()),
+        });
+        let shown = |key: &str| rows.iter().find(|(k, _)| *k == key).unwrap().1.clone();
+
+        let pair = |value: &str, source: &str| Some((value.to_owned(), source.to_owned()));
+        assert_eq!(shown("uploader-path"), pair("/env/mik32-uploader", "environment MIK32_UPLOADER_PATH"));
+        assert_eq!(shown("openocd-path"), pair("tools/openocd", LOCAL_CONFIG_FILE));
+        assert_eq!(shown("gdb-exec"), pair("/usr/bin/gdb-multiarch", "PATH"));
+        assert_eq!(shown("openocd-host"), None);
+    }
+
+    #[test]
+    fn command_line_then_env_then_profile_then_files() {
+        let config = config(&[
+            ("adapter-speed", "200", Source::Env("MIK32_ADAPTER_SPEED")),
+            ("openocd-port", "6001", Source::Env("MIK32_OPENOCD_PORT")),
+            ("gdb-port", "3002", Source::Local),
+            ("openocd-interface", "interface/files.cfg", Source::Metadata),
+        ]);
+        let profile = ProbeProfile {
+            interface: None,
+            adapter_speed: Some(300),
+            host: None,
+            port: Some(6002),
+            gdb_port: Some(3001),
+            serial: None,
+        };
+        let mut desc = FlashCmdDescriptor { adapter_speed: Some("100".to_owned()), ..Default::default() };
+        config.apply_layers(&mut desc, Some(&profile)).unwrap();
+
+        assert_eq!(desc.adapter_speed.as_deref(), Some("100"), "command line wins over everything");
+        assert_eq!(desc.openocd_port.as_deref(), Some("6001"), "environment wins over profile");
+        assert_eq!(desc.gdb_port.as_deref(), Some("3001"), "profile wins over config files");
+        assert_eq!(desc.openocd_interface, Some(PathBuf::from("interface/files.cfg")), "config files fill the rest");
+        assert_eq!(desc.openocd_host, None, "unset keys keep their default");
+        assert!(!desc.use_quad_spi);
+    }
+
+    #[test]
+    fn bad_value_names_its_layer() {
+        let config = config(&[("gdb-port", "gdb", Source::Env("MIK32_GDB_PORT"))]);
+        let error = config.apply_layers(&mut FlashCmdDescriptor::default(), None).unwrap_err();
+        assert_eq!(error.to_string(), "invalid value 'gdb' for 'gdb-port' from environment MIK32_GDB_PORT");
+    }
+}
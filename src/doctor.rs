@@ -0,0 +1,224 @@
+use std::{path::{Path, PathBuf}, process::Command};
+
+use crate::{chip::{chip, RUST_TARGET}, exit_code, config::{ConfigError, ProjectConfig}, tools::{self, Tool, ToolError}, FlashCmdDescriptor};
+
+const UPLOADER_REPO: &str = "https://github.com/MikronMIK32/mik32-uploader";
+
+#[derive(Debug)]
+pub enum DoctorError {
+    BadConfig(ConfigError),
+    ChecksFailed(usize),
+}
+
+impl DoctorError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DoctorError::BadConfig(e) => e.exit_code(),
+            DoctorError::ChecksFailed(_) => exit_code::CHECKS_FAILED,
+        }
+    }
+}
+
+impl std::fmt::Display for DoctorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DoctorError::BadConfig(e) => write!(f, "failed to load project configuration, {}", e),
+            DoctorError::ChecksFailed(count) => write!(f, "{} check(s) failed", count),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum Status {
+    Ok,
+    /// Missing piece that only some backends or options need.
+    Warn,
+    Fail,
+}
+
+struct Check {
+    name: String,
+    status: Status,
+    detail: String,
+    hint: Option<String>,
+    /// Command that fixes the problem without side effects outside the toolchain, applied with `--fix`.
+    fix: Option<Vec<&'static str>>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Check { name: name.into(), status, detail: detail.into(), hint: None, fix: None }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    fn fix(mut self, fix: &[&'static str]) -> Self {
+        self.fix = Some(fix.to_vec());
+        self
+    }
+}
+
+/// `cargo mik32 doctor`: checks toolchain, uploader and openocd setup up front instead of midway through `run`.
+/// Paths come from project configuration when run inside a project. With `fix` safe fixes, like
+/// installing the rustup target, are applied.
+pub fn doctor(project_dir: PathBuf, fix: bool) -> Result<(), DoctorError> {
+    let mut desc = FlashCmdDescriptor { project_dir, ..Default::default() };
+    if desc.project_dir.join("Cargo.toml").exists() {
+        ProjectConfig::load(&desc.project_dir)
+            .and_then(|config| config.apply(&mut desc))
+            .map_err(DoctorError::BadConfig)?;
+    }
+
+    let checks = [
+        check_rust_target(),
+        check_llvm_tools(),
+        check_tool(&desc, Tool::Objcopy, None, false, "only needed with '--objcopy cargo'", "cargo install cargo-binutils"),
+        check_tool(&desc, Tool::Python, None, false, "only needed by 'python' backend", "install python3 and make sure it is in PATH"),
+        check_tool(
+            &desc,
+            Tool::Uploader,
+            desc.uploader_path.as_deref(),
+            false,
+            "only needed by 'python' backend",
+            &format!("git clone {} flash/mik32-uploader, or set MIK32_UPLOADER_PATH", UPLOADER_REPO),
+        ),
+        check_tool(
+            &desc,
+            Tool::Openocd,
+            desc.openocd_path.as_deref(),
+            true,
+            "",
+            "install openocd with MIK32 support, or set MIK32_OPENOCD_PATH / openocd-path",
+        ),
+        check_openocd_scripts(&desc),
+        check_tool(
+            &desc,
+            Tool::Gdb,
+            desc.gdb_exec.as_deref().map(Path::new),
+            false,
+            "debugging is unavailable",
+            "install gdb-multiarch",
+        ),
+    ];
+
+    let mut failed = 0;
+    let mut warnings = 0;
+    for check in checks {
+        let mut status = check.status;
+        let label = match status {
+            Status::Ok => "[ OK ]",
+            Status::Warn => "[WARN]",
+            Status::Fail => "[FAIL]",
+        };
+        println!("{} {}: {}", label, check.name, check.detail);
+        if status != Status::Ok {
+            if let (true, Some(command)) = (fix, &check.fix) {
+                println!("       fixing: {}", command.join(" "));
+                match Command::new(command[0]).args(&command[1..]).status() {
+                    Ok(exit) if exit.success() => status = Status::Ok,
+                    _ => eprintln!("       fix failed"),
+                }
+            } else if let Some(hint) = &check.hint {
+                println!("       hint: {}", hint);
+            }
+        }
+        match status {
+            Status::Ok => (),
+            Status::Warn => warnings += 1,
+            Status::Fail => failed += 1,
+        }
+    }
+
+    println!();
+    if failed == 0 {
+        println!("All required tools found, {} warning(s).", warnings);
+        Ok(())
+    } else {
+        if !fix {
+            println!("Some problems can be fixed automatically with 'cargo mik32 doctor --fix'.");
+        }
+        Err(DoctorError::ChecksFailed(failed))
+    }
+}
+
+fn check_rust_target() -> Check {
+    let name = format!("rustup target {}", RUST_TARGET);
+    match command_output("rustup", &["target", "list", "--installed"]) {
+        None => Check::new(name, Status::Fail, "rustup not found").hint("install rustup from https://rustup.rs"),
+        Some(installed) if installed.lines().any(|l| l.trim() == RUST_TARGET) => Check::new(name, Status::Ok, "installed"),
+        Some(_) => Check::new(name, Status::Fail, "not installed")
+            .hint(format!("rustup target add {}", RUST_TARGET))
+            .fix(&["rustup", "target", "add", RUST_TARGET]),
+    }
+}
+
+fn check_llvm_tools() -> Check {
+    let name = "llvm-tools";
+    match command_output("rustup", &["component", "list", "--installed"]) {
+        Some(installed) if installed.lines().any(|l| l.starts_with("llvm-tools")) => Check::new(name, Status::Ok, "installed"),
+        _ => Check::new(name, Status::Warn, "not installed, only needed with '--objcopy cargo'")
+            .hint("rustup component add llvm-tools")
+            .fix(&["rustup", "component", "add", "llvm-tools"]),
+    }
+}
+
+/// Resolver based check. Missing optional tools are warnings, a provided but broken tool is always a failure.
+fn check_tool(desc: &FlashCmdDescriptor, tool: Tool, provided: Option<&Path>, required: bool, missing: &str, hint: &str) -> Check {
+    match tools::resolve(tool, provided, &desc.project_dir) {
+        Ok(resolved) => Check::new(
+            tool.name(),
+            Status::Ok,
+            format!(
+                "{} ({}{})",
+                resolved.path.display(),
+                resolved.source,
+                resolved.version.map(|v| format!(", {}", v)).unwrap_or_default()
+            ),
+        ),
+        Err(e @ ToolError::NotFound { .. }) if !required => Check::new(tool.name(), Status::Warn, format!("{}, {}", e, missing)).hint(hint),
+        Err(e) => Check::new(tool.name(), Status::Fail, e.to_string()).hint(hint),
+    }
+}
+
+/// Scripts directory must hold interface and target configs, unless openocd built-in scripts are used.
+fn check_openocd_scripts(desc: &FlashCmdDescriptor) -> Check {
+    let scripts = desc.openocd_scripts
+        .clone()
+        .or_else(|| {
+            tools::resolve(Tool::Uploader, desc.uploader_path.as_deref(), &desc.project_dir)
+                .ok()
+                .map(|uploader| uploader.path.join("openocd-scripts"))
+                .filter(|p| p.exists())
+        });
+    let Some(scripts) = scripts else {
+        return Check::new("openocd scripts", Status::Warn, "none found, openocd built-in scripts will be used")
+            .hint("pass --openocd-scripts or set openocd-scripts, if openocd does not ship MIK32 configs");
+    };
+    let target = desc.openocd_target
+        .clone()
+        .unwrap_or(PathBuf::from(chip(desc.mcu_type.as_ref()).openocd_target));
+    let missing: Vec<String> = [desc.openocd_interface.clone(), Some(target)]
+        .into_iter()
+        .flatten()
+        .filter(|cfg| !scripts.join(cfg).exists())
+        .map(|cfg| cfg.display().to_string())
+        .collect();
+    if missing.is_empty() {
+        Check::new("openocd scripts", Status::Ok, scripts.display().to_string())
+    } else {
+        Check::new(
+            "openocd scripts",
+            Status::Fail,
+            format!("{} is missing {}", scripts.display(), missing.join(", ")),
+        )
+        .hint("point --openocd-scripts to a directory with MIK32 configs")
+    }
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
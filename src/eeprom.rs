@@ -0,0 +1,261 @@
+use std::{thread::sleep, time::{Duration, Instant}};
+
+use crate::{chip::MemoryRegion, ihex::{split_into_pages, to_words, Segment}, tcl_client::{TclClient, TclError}};
+
+const EEPROM_REGS: u32 = 0x0007_0400;
+const EEPROM_REGS_EEDAT: u32 = EEPROM_REGS;
+const EEPROM_REGS_EEA: u32 = EEPROM_REGS + 0x04;
+const EEPROM_REGS_EECON: u32 = EEPROM_REGS + 0x08;
+const EEPROM_REGS_EESTA: u32 = EEPROM_REGS + 0x0C;
+const EEPROM_REGS_NCYCRL: u32 = EEPROM_REGS + 0x14;
+const EEPROM_REGS_NCYCEP1: u32 = EEPROM_REGS + 0x18;
+const EEPROM_REGS_NCYCEP2: u32 = EEPROM_REGS + 0x1C;
+
+const EECON_EX: u32 = 1 << 0;
+const EECON_OP_S: u32 = 1;
+const EECON_BWE: u32 = 1 << 7;
+const EESTA_BSY: u32 = 1 << 0;
+
+const OP_ER: u32 = 1;
+const OP_PR: u32 = 2;
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum EepromError {
+    Tcl(TclError),
+    OutOfRange(u32),
+    Timeout,
+    VerifyFailed(u32),
+}
+
+impl std::fmt::Display for EepromError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EepromError::Tcl(e) => write!(f, "{}", e),
+            EepromError::OutOfRange(address) => write!(f, "address 0x{:08x} is outside of EEPROM", address),
+            EepromError::Timeout => write!(f, "EEPROM controller stayed busy for too long"),
+            EepromError::VerifyFailed(address) => write!(f, "verification failed at page 0x{:08x}", address),
+        }
+    }
+}
+
+impl From<TclError> for EepromError {
+    fn from(err: TclError) -> Self {
+        EepromError::Tcl(err)
+    }
+}
+
+/// Programs image segments into on-chip EEPROM the same way mik32_upload.py does:
+/// every touched page is erased, written through the EEDAT buffer and read back.
+/// Bytes of a touched page not covered by the image are written as zeros.
+/// `region` is the EEPROM window of the selected chip. Target has to be halted beforehand.
+pub fn program(
+    client: &mut TclClient,
+    region: &MemoryRegion,
+    segments: &[Segment],
+    mut on_page: impl FnMut(usize, usize),
+) -> Result<(), EepromError> {
+    let pages = split_into_pages(segments, region.start, region.size, region.page_size, 0).map_err(EepromError::OutOfRange)?;
+
+    sysinit(client)?;
+    for (done, (offset, page)) in pages.iter().enumerate() {
+        erase_page(client, region, *offset)?;
+        write_page(client, *offset, page)?;
+        verify_page(client, region, *offset, page)?;
+        on_page(done + 1, pages.len());
+    }
+    Ok(())
+}
+
+fn sysinit(client: &mut TclClient) -> Result<(), EepromError> {
+    client.write_word(EEPROM_REGS_NCYCRL, 1 | (3 << 8) | (1 << 16))?;
+    client.write_word(EEPROM_REGS_NCYCEP1, 100_000)?;
+    client.write_word(EEPROM_REGS_NCYCEP2, 1000)?;
+    sleep(Duration::from_millis(100));
+    Ok(())
+}
+
+fn erase_page(client: &mut TclClient, region: &MemoryRegion, offset: u32) -> Result<(), EepromError> {
+    client.write_word(EEPROM_REGS_EECON, EECON_BWE)?;
+    client.write_word(EEPROM_REGS_EEA, offset)?;
+    client.write_repeated(EEPROM_REGS_EEDAT, 32, &vec![0; (region.erase_size / 4) as usize])?;
+    client.write_word(EEPROM_REGS_EECON, EECON_EX | EECON_BWE | (OP_ER << EECON_OP_S))?;
+    wait_ready(client)
+}
+
+fn write_page(client: &mut TclClient, offset: u32, page: &[u8]) -> Result<(), EepromError> {
+    client.write_word(EEPROM_REGS_EECON, EECON_BWE)?;
+    client.write_word(EEPROM_REGS_EEA, offset)?;
+    client.write_repeated(EEPROM_REGS_EEDAT, 32, &to_words(page))?;
+    client.write_word(EEPROM_REGS_EECON, EECON_EX | EECON_BWE | (OP_PR << EECON_OP_S))?;
+    wait_ready(client)
+}
+
+fn verify_page(client: &mut TclClient, region: &MemoryRegion, offset: u32, page: &[u8]) -> Result<(), EepromError> {
+    let words = client.read_memory(region.start + offset, 32, page.len() as u32 / 4)?;
+    if words != to_words(page) {
+        return Err(EepromError::VerifyFailed(region.start + offset));
+    }
+    Ok(())
+}
+
+/// Polls EESTA until the controller drops its busy flag.
+fn wait_ready(client: &mut TclClient) -> Result<(), EepromError> {
+    let deadline = Instant::now() + BUSY_TIMEOUT;
+    while client.read_word(EEPROM_REGS_EESTA)? & EESTA_BSY != 0 {
+        if Instant::now() >= deadline {
+            return Err(EepromError::Timeout);
+        }
+        sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chip::chip, tcl_client::fake::{self, Target}};
+
+    const WORDS: usize = 8 * 1024 / 4;
+
+    /// EEPROM controller model: EEDAT collects words for the page at EEA, EECON runs erase or program,
+    /// EESTA reports busy for `busy_polls` reads after every operation, and the array is readable at 0x01000000.
+    struct Simulated {
+        memory: Vec<u32>,
+        address: u32,
+        buffer: Vec<u32>,
+        busy_polls: u32,
+        busy_left: u32,
+        stuck_busy: bool,
+        /// Readback of this address returns inverted data.
+        corrupt: Option<u32>,
+        status_reads: u32,
+        writes: usize,
+        programmed: Vec<(u32, Vec<u32>)>,
+    }
+
+    impl Simulated {
+        fn new() -> Self {
+            Simulated {
+                memory: vec![0xFFFF_FFFF; WORDS],
+                address: 0,
+                buffer: Vec::new(),
+                busy_polls: 0,
+                busy_left: 0,
+                stuck_busy: false,
+                corrupt: None,
+                status_reads: 0,
+                writes: 0,
+                programmed: Vec::new(),
+            }
+        }
+    }
+
+    impl Target for Simulated {
+        fn read(&mut self, address: u32, _width: u32) -> u32 {
+            if address == EEPROM_REGS_EESTA {
+                self.status_reads += 1;
+                if self.stuck_busy || self.busy_left > 0 {
+                    self.busy_left = self.busy_left.saturating_sub(1);
+                    return EESTA_BSY;
+                }
+                return 0;
+            }
+            let region = &chip(None).eeprom;
+            if region.contains(address) {
+                let word = self.memory[((address - region.start) / 4) as usize];
+                return if self.corrupt == Some(address) { !word } else { word };
+            }
+            0
+        }
+
+        fn write(&mut self, address: u32, _width: u32, value: u32) {
+            self.writes += 1;
+            match address {
+                EEPROM_REGS_EEDAT => self.buffer.push(value),
+                EEPROM_REGS_EEA => {
+                    self.address = value;
+                    self.buffer.clear();
+                }
+                EEPROM_REGS_EECON if value & EECON_EX != 0 => {
+                    let first = (self.address / 4) as usize;
+                    match (value >> EECON_OP_S) & 0b11 {
+                        OP_ER => self.memory[first..first + 32].fill(0),
+                        OP_PR => {
+                            self.memory[first..first + self.buffer.len()].copy_from_slice(&self.buffer);
+                            self.programmed.push((self.address, self.buffer.clone()));
+                        }
+                        _ => {}
+                    }
+                    self.busy_left = self.busy_polls;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn program_simulated(target: Simulated, segments: &[Segment]) -> (Result<(), EepromError>, Simulated, Vec<(usize, usize)>) {
+        let mut progress = Vec::new();
+        let (result, target) = fake::session(target, |client| {
+            program(client, &chip(None).eeprom, segments, |done, total| progress.push((done, total)))
+        });
+        (result, target, progress)
+    }
+
+    #[test]
+    fn pages_are_split_and_zero_padded() {
+        // 16 bytes across the boundary of the first two pages.
+        let data: Vec<u8> = (1..=16).collect();
+        let segment = Segment { address: 0x0100_0000 + 120, data };
+        let (result, target, progress) = program_simulated(Simulated::new(), &[segment]);
+        result.unwrap();
+
+        let mut first = vec![0u32; 32];
+        first[30] = u32::from_le_bytes([1, 2, 3, 4]);
+        first[31] = u32::from_le_bytes([5, 6, 7, 8]);
+        let mut second = vec![0u32; 32];
+        second[0] = u32::from_le_bytes([9, 10, 11, 12]);
+        second[1] = u32::from_le_bytes([13, 14, 15, 16]);
+        assert_eq!(target.programmed, vec![(0, first), (128, second)]);
+        assert_eq!(progress, vec![(1, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn busy_flag_is_polled_until_clear() {
+        let target = Simulated { busy_polls: 3, ..Simulated::new() };
+        let segment = Segment { address: 0x0100_0000, data: vec![0x55; 4] };
+        let (result, target, _) = program_simulated(target, &[segment]);
+        result.unwrap();
+        // Erase and program of one page, each polled through three busy reads and one ready read.
+        assert_eq!(target.status_reads, 8);
+    }
+
+    #[test]
+    fn stuck_busy_flag_times_out() {
+        let target = Simulated { stuck_busy: true, ..Simulated::new() };
+        let segment = Segment { address: 0x0100_0000, data: vec![0x55; 4] };
+        let (result, _, _) = program_simulated(target, &[segment]);
+        assert!(matches!(result, Err(EepromError::Timeout)));
+    }
+
+    #[test]
+    fn readback_mismatch_fails_verify() {
+        let target = Simulated { corrupt: Some(0x0100_0084), ..Simulated::new() };
+        let segment = Segment { address: 0x0100_0000, data: vec![0x55; 256] };
+        let (result, _, progress) = program_simulated(target, &[segment]);
+        assert!(matches!(result, Err(EepromError::VerifyFailed(0x0100_0080))));
+        assert_eq!(progress, vec![(1, 2)]);
+    }
+
+    #[test]
+    fn address_outside_eeprom_is_rejected_before_writing() {
+        let segments = [
+            Segment { address: 0x0100_0000, data: vec![0x55; 4] },
+            Segment { address: 0x0100_1FFE, data: vec![0x55; 4] },
+        ];
+        let (result, target, _) = program_simulated(Simulated::new(), &segments);
+        assert!(matches!(result, Err(EepromError::OutOfRange(0x0100_2000))));
+        assert_eq!(target.writes, 0);
+    }
+}
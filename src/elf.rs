@@ -0,0 +1,177 @@
+use std::{fs, path::Path};
+
+use crate::ihex::{HexError, HexImage, Segment};
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+
+#[derive(Debug)]
+pub enum ElfError {
+    Io(std::io::Error),
+    NotElf,
+    Unsupported(&'static str),
+    Truncated,
+    Layout(HexError),
+}
+
+impl std::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ElfError::Io(e) => write!(f, "failed to read ELF file: {}", e),
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Unsupported(what) => write!(f, "unsupported ELF: {}", what),
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::Layout(e) => write!(f, "bad segment layout: {}", e),
+        }
+    }
+}
+
+/// Reads loadable segments of a 32-bit little-endian RISC-V ELF into an image.
+/// Segments are placed at their physical (load) addresses, the same way `objcopy -O ihex` does,
+/// so initialized data ends up in flash next to the code. ELF entry becomes the image start address.
+pub fn read_file(path: &Path) -> Result<HexImage, ElfError> {
+    let bytes = fs::read(path).map_err(ElfError::Io)?;
+    parse(&bytes)
+}
+
+pub fn parse(bytes: &[u8]) -> Result<HexImage, ElfError> {
+    if bytes.len() < 52 || &bytes[..4] != ELF_MAGIC {
+        return Err(ElfError::NotElf);
+    }
+    if bytes[4] != ELFCLASS32 {
+        return Err(ElfError::Unsupported("only 32-bit ELF files are supported"));
+    }
+    if bytes[5] != ELFDATA2LSB {
+        return Err(ElfError::Unsupported("only little-endian ELF files are supported"));
+    }
+    if read_u16(bytes, 18)? != EM_RISCV {
+        return Err(ElfError::Unsupported("not a RISC-V executable"));
+    }
+
+    let entry = read_u32(bytes, 24)?;
+    let phoff = read_u32(bytes, 28)? as usize;
+    let phentsize = read_u16(bytes, 42)? as usize;
+    let phnum = read_u16(bytes, 44)? as usize;
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let header = phoff + i * phentsize;
+        if read_u32(bytes, header)? != PT_LOAD {
+            continue;
+        }
+        let offset = read_u32(bytes, header + 4)? as usize;
+        let paddr = read_u32(bytes, header + 12)?;
+        let filesz = read_u32(bytes, header + 16)? as usize;
+        if filesz == 0 {
+            continue;
+        }
+        let data = bytes.get(offset..offset + filesz).ok_or(ElfError::Truncated)?;
+        segments.push(Segment { address: paddr, data: data.to_vec() });
+    }
+
+    HexImage::from_segments(segments, Some(entry)).map_err(ElfError::Layout)
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> Result<u16, ElfError> {
+    bytes
+        .get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Result<u32, ElfError> {
+    bytes
+        .get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(ElfError::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PT_NOTE: u32 = 4;
+    const EHDR_SIZE: usize = 52;
+    const PHDR_SIZE: usize = 32;
+
+    struct ProgramHeader {
+        kind: u32,
+        vaddr: u32,
+        paddr: u32,
+        data: Vec<u8>,
+    }
+
+    /// Minimal RV32 executable: ELF header, program header table right after it, then segment data.
+    fn build(entry: u32, headers: &[ProgramHeader]) -> Vec<u8> {
+        let mut bytes = vec![0u8; EHDR_SIZE];
+        bytes[..4].copy_from_slice(ELF_MAGIC);
+        bytes[4] = ELFCLASS32;
+        bytes[5] = ELFDATA2LSB;
+        bytes[6] = 1;
+        bytes[16..18].copy_from_slice(&2u16.to_le_bytes());
+        bytes[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        bytes[24..28].copy_from_slice(&entry.to_le_bytes());
+        bytes[28..32].copy_from_slice(&(EHDR_SIZE as u32).to_le_bytes());
+        bytes[40..42].copy_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+        bytes[42..44].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        bytes[44..46].copy_from_slice(&(headers.len() as u16).to_le_bytes());
+
+        let mut offset = EHDR_SIZE + headers.len() * PHDR_SIZE;
+        for header in headers {
+            let fields = [header.kind, offset as u32, header.vaddr, header.paddr, header.data.len() as u32, header.data.len() as u32, 0, 4];
+            bytes.extend(fields.iter().flat_map(|field| field.to_le_bytes()));
+            offset += header.data.len();
+        }
+        for header in headers {
+            bytes.extend_from_slice(&header.data);
+        }
+        bytes
+    }
+
+    #[test]
+    fn segments_are_placed_at_physical_addresses() {
+        let bytes = build(
+            0x8000_0000,
+            &[
+                ProgramHeader { kind: PT_LOAD, vaddr: 0x8000_0000, paddr: 0x8000_0000, data: vec![1, 2, 3, 4] },
+                // Initialized .data runs from RAM but is loaded into flash right after the code.
+                ProgramHeader { kind: PT_LOAD, vaddr: 0x0200_0000, paddr: 0x8000_0004, data: vec![5, 6] },
+            ],
+        );
+        let image = parse(&bytes).unwrap();
+        assert_eq!(image.segments, vec![Segment { address: 0x8000_0000, data: vec![1, 2, 3, 4, 5, 6] }]);
+        assert_eq!(image.start_address, Some(0x8000_0000));
+    }
+
+    #[test]
+    fn empty_and_non_loadable_segments_are_skipped() {
+        let bytes = build(
+            0x0100_0000,
+            &[
+                ProgramHeader { kind: PT_NOTE, vaddr: 0, paddr: 0, data: vec![9; 8] },
+                // .bss: nothing to load.
+                ProgramHeader { kind: PT_LOAD, vaddr: 0x0200_0000, paddr: 0x0200_0000, data: Vec::new() },
+                ProgramHeader { kind: PT_LOAD, vaddr: 0x0100_0000, paddr: 0x0100_0000, data: vec![1, 2] },
+            ],
+        );
+        let image = parse(&bytes).unwrap();
+        assert_eq!(image.segments, vec![Segment { address: 0x0100_0000, data: vec![1, 2] }]);
+    }
+
+    #[test]
+    fn truncated_program_header_is_rejected() {
+        let header = ProgramHeader { kind: PT_LOAD, vaddr: 0x0100_0000, paddr: 0x0100_0000, data: vec![1, 2] };
+        let bytes = build(0x0100_0000, &[header]);
+        assert!(matches!(parse(&bytes[..EHDR_SIZE + 14]), Err(ElfError::Truncated)));
+    }
+
+    #[test]
+    fn elf64_is_rejected() {
+        let mut bytes = build(0, &[]);
+        bytes[4] = 2;
+        assert!(matches!(parse(&bytes), Err(ElfError::Unsupported(_))));
+    }
+}
@@ -0,0 +1,68 @@
+use std::{io::Write, path::Path, sync::OnceLock};
+
+use serde::Serialize;
+
+use crate::MessageFormat;
+
+/// Pipeline events for `--message-format json`, one JSON object per line on stdout.
+/// Tagged with `reason` like cargo's own JSON messages. Human readable output goes to stderr either way.
+#[derive(Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    BuildStarted,
+    BuildFinished { success: bool },
+    Artifact { name: &'a str, executable: String },
+    Image { path: String, size: usize, segments: Vec<Segment>, boot_mode: Option<&'a str> },
+    ToolResolved { tool: &'a str, path: String, source: String, version: Option<&'a str> },
+    UploadStarted { backend: &'a str, boot_mode: Option<&'a str> },
+    UploadProgress { done: usize, total: usize },
+    UploadFinished,
+    Verify { success: bool, message: Option<String> },
+    GdbStarted { gdb: String, elf: String },
+    /// Step `--dry-run` skipped: a command line, or a description for steps done in-process.
+    DryRun { step: &'a str, detail: &'a str },
+    Error { code: i32, message: &'a str },
+}
+
+#[derive(Serialize)]
+pub struct Segment {
+    pub address: u32,
+    pub size: usize,
+}
+
+static FORMAT: OnceLock<MessageFormat> = OnceLock::new();
+
+pub fn init(format: MessageFormat) {
+    let _ = FORMAT.set(format);
+}
+
+/// Whether stdout is reserved for events. Subprocess output and progress bars then stay off stdout.
+pub fn json() -> bool {
+    matches!(FORMAT.get(), Some(MessageFormat::Json))
+}
+
+pub fn emit(event: Event) {
+    if !json() {
+        return;
+    }
+    // Events hold plain data, serialization cannot fail.
+    let line = serde_json::to_string(&event).expect("event serializes");
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{}", line);
+    let _ = stdout.flush();
+}
+
+/// Reports a step skipped by `--dry-run`. This is the output of a dry run, so it goes to stdout even with `-q`.
+pub fn dry_run(step: &str, detail: &str) {
+    if json() {
+        emit(Event::DryRun { step, detail });
+    } else if detail.contains('\n') {
+        println!("[dry-run] {}:\n{}", step, detail);
+    } else {
+        println!("[dry-run] {}: {}", step, detail);
+    }
+}
+
+pub fn path(path: &Path) -> String {
+    path.display().to_string()
+}
@@ -0,0 +1,38 @@
+//! Process exit codes, one per failure class, so scripts can tell e.g. a missing probe from a broken build.
+
+/// Bad arguments or configuration, not a cargo project, project already exists. Same code clap uses.
+pub const USAGE: i32 = 2;
+/// Required tool (openocd, python, uploader, gdb, cargo-objcopy) is missing or does not run.
+pub const TOOL_MISSING: i32 = 3;
+pub const BUILD_FAILED: i32 = 4;
+/// Hex or ELF image is broken, empty or does not fit the boot memory.
+pub const BAD_IMAGE: i32 = 5;
+/// openocd could not reach the probe or the target.
+pub const PROBE_NOT_FOUND: i32 = 6;
+pub const UPLOAD_FAILED: i32 = 7;
+pub const GDB_FAILED: i32 = 8;
+/// Failed to read or write a file.
+pub const IO: i32 = 9;
+/// `doctor` found problems.
+pub const CHECKS_FAILED: i32 = 10;
+/// `init` could not add dependencies.
+pub const FETCH_FAILED: i32 = 11;
+/// Another `run` holds the probe lock.
+pub const PROBE_BUSY: i32 = 12;
+// Interrupted by a signal: 128 + signal number like a shell reports it, so 130 for Ctrl-C. See `process::interrupted`.
+
+pub const HELP: &str = "\
+Exit codes:
+  0   success
+  2   bad arguments or configuration
+  3   required tool is missing
+  4   build failed
+  5   bad image
+  6   probe or target not found
+  7   upload failed
+  8   gdb failed
+  9   file access failed
+  10  doctor found problems
+  11  failed to fetch dependencies
+  12  probe is used by another run
+  130 interrupted by Ctrl-C, 128 + signal number for other signals";
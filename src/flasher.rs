@@ -0,0 +1,468 @@
+use std::{path::{absolute, Path, PathBuf}, process::{Command, Stdio}};
+
+use log::{debug, info};
+
+use crate::{chip::chip, build_script::{boot_mode_name, find_tool, RunError}, eeprom, events::{self, Event}, ihex, logger, process::{self, StderrTail, Supervised}, server::{self, Ports, Session}, spifi, tcl_client::{TclClient, TclError}, tools::{self, Tool, ToolError}, Backend, BootMode, FlashCmdDescriptor};
+
+const DEFAULT_OPENOCD_INTERFACE: &str = "interface/ftdi/m-link.cfg";
+
+/// Upload backend. Each one takes an already built hex image from `desc.app_hex_path` and puts it onto the board.
+pub trait Flasher {
+    fn name(&self) -> &'static str;
+    fn flash(&self, desc: &FlashCmdDescriptor) -> Result<(), RunError>;
+}
+
+/// Runs mik32_upload.py from mik32-uploader. Needs python3.
+pub struct PythonFlasher;
+
+/// Programs EEPROM and SPIFI flash over openocd TCL port, without python.
+pub struct NativeFlasher;
+
+/// Uses plain openocd `program`. Needs an openocd build that knows how to write the target memory.
+pub struct OpenocdFlasher;
+
+impl Flasher for PythonFlasher {
+    fn name(&self) -> &'static str {
+        "python"
+    }
+
+    fn flash(&self, desc: &FlashCmdDescriptor) -> Result<(), RunError> {
+        upload_python(desc)
+    }
+}
+
+impl Flasher for NativeFlasher {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn flash(&self, desc: &FlashCmdDescriptor) -> Result<(), RunError> {
+        match desc.boot_mode {
+            Some(BootMode::Eeprom) => upload_eeprom(desc),
+            Some(BootMode::Spifi) => upload_spifi(desc),
+            _ => Err(RunError::BadArgument(
+                "native backend can only program EEPROM and SPIFI, pick another --backend".to_owned(),
+            )),
+        }
+    }
+}
+
+impl Flasher for OpenocdFlasher {
+    fn name(&self) -> &'static str {
+        "openocd"
+    }
+
+    fn flash(&self, desc: &FlashCmdDescriptor) -> Result<(), RunError> {
+        upload_openocd(desc)
+    }
+}
+
+/// Picks backend requested with --backend. Without it EEPROM and SPIFI go native and everything else goes to python uploader.
+pub fn select_flasher(backend: Option<&Backend>, boot_mode: Option<&BootMode>) -> Box<dyn Flasher> {
+    match (backend, boot_mode) {
+        (Some(Backend::Python), _) => Box::new(PythonFlasher),
+        (Some(Backend::Native), _) => Box::new(NativeFlasher),
+        (Some(Backend::Openocd), _) => Box::new(OpenocdFlasher),
+        (None, Some(BootMode::Eeprom | BootMode::Spifi)) => Box::new(NativeFlasher),
+        (None, _) => Box::new(PythonFlasher),
+    }
+}
+
+/// Upload procedure resolves uploader and python with the tool resolver and runs mik32_upload.py.
+/// The uploader would start openocd on its default ports, colliding with sessions on other probes,
+/// so it connects to an openocd session like the native backend uses: remote, background or private.
+fn upload_python(desc: &FlashCmdDescriptor) -> Result<(), RunError> {
+    let uploader_final_path = find_tool(Tool::Uploader, desc.uploader_path.as_deref(), &desc.project_dir)?;
+    let python_path = find_tool(Tool::Python, None, &desc.project_dir)?;
+    info!("Preparing to upload...");
+
+    let app_hex_path = app_hex_path(desc)?;
+    let openocd = Session::open(desc, false)?;
+    let upload_cmd = python_command(desc, &python_path, &uploader_final_path, &app_hex_path, &openocd)?;
+
+    if desc.dry_run {
+        events::dry_run("mik32_upload.py", &process::describe(&upload_cmd));
+        return Ok(());
+    }
+
+    info!("Uploading binary...");
+
+    let result = run_upload(upload_cmd, "mik32_upload.py", Tool::Python, &python_path);
+    openocd.close();
+    result?;
+    
+    info!("Aplication uploaded successfully");
+    Ok(())
+}
+
+/// mik32_upload.py invocation talking to the openocd of `openocd`. Probe and target settings
+/// belong to that openocd, the uploader only needs its TCL port.
+fn python_command(
+    desc: &FlashCmdDescriptor,
+    python_path: &Path,
+    uploader_path: &Path,
+    app_hex_path: &Path,
+    openocd: &Session,
+) -> Result<Command, RunError> {
+    let mut upload_cmd = Command::new(python_path);
+    upload_cmd.arg(absolute_path(&uploader_path.join("mik32_upload.py"))?);
+    upload_cmd.arg(app_hex_path);
+    
+    if desc.use_quad_spi {
+        upload_cmd.arg("--use-quad-spi");
+    }
+
+    if let Some(boot_mode) = &desc.boot_mode {
+        upload_cmd.args([
+            "--boot-mode",
+            boot_mode_name(boot_mode)
+        ]);
+    }
+
+    upload_cmd.args([
+        "--openocd-host",
+        &openocd.host,
+        "--openocd-port",
+        &openocd.ports.tcl.to_string(),
+    ]);
+    Ok(upload_cmd)
+}
+
+/// Runs an upload tool with its output passed through and logged, stderr is kept for the error message.
+/// `program` is the resolved `tool` executable, reported when it cannot be started.
+fn run_upload(mut command: Command, label: &'static str, tool: Tool, program: &Path) -> Result<(), RunError> {
+    debug!("Running {}", process::describe(&command));
+    let mut child = Supervised::spawn(command.stdout(Stdio::piped()).stderr(Stdio::piped()))
+        .map_err(|e| spawn_failed(tool, program, e))?;
+    let stderr = StderrTail::capture_all(&mut child, label, true);
+    let status = child.wait();
+    let stderr = stderr.finish();
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(RunError::UploadFailed { tool: label, reason: format!("exited with {}", status), stderr }),
+        Err(e) => Err(RunError::UploadFailed { tool: label, reason: e.to_string(), stderr }),
+    }
+}
+
+fn spawn_failed(tool: Tool, path: &Path, error: std::io::Error) -> RunError {
+    RunError::Tool(ToolError::Invalid { tool, path: path.to_path_buf(), reason: error.to_string() })
+}
+
+fn absolute_path(path: &Path) -> Result<PathBuf, RunError> {
+    absolute(path).map_err(|e| RunError::io(path, e))
+}
+
+fn app_hex_path(desc: &FlashCmdDescriptor) -> Result<PathBuf, RunError> {
+    match &desc.app_hex_path {
+        Some(path) => absolute_path(path),
+        None => Err(RunError::BadArgument("no hex image to upload, pass --app-hex-path".to_owned())),
+    }
+}
+
+/// Picks openocd scripts directory for native and openocd uploads: provided value, otherwise 'openocd-scripts'
+/// of mik32-uploader if it can be found. When nothing is found openocd falls back to its own scripts.
+fn native_openocd_scripts(desc: &FlashCmdDescriptor) -> Option<PathBuf> {
+    if desc.openocd_scripts.is_some() {
+        return desc.openocd_scripts.clone();
+    }
+    tools::resolve(Tool::Uploader, desc.uploader_path.as_deref(), &desc.project_dir)
+        .ok()
+        .map(|uploader| uploader.path.join("openocd-scripts"))
+        .filter(|path| path.exists())
+}
+
+/// Builds openocd invocation with scripts directory, interface, adapter speed and target configured.
+/// Extra `-c` commands are placed before the config files, so they may only set ports and alike.
+pub fn openocd_command(openocd_path: &Path, desc: &FlashCmdDescriptor, pre_commands: &[String]) -> Command {
+    let mut openocd = Command::new(openocd_path);
+    if let Some(scripts) = native_openocd_scripts(desc) {
+        openocd.arg("-s").arg(absolute(&scripts).unwrap_or(scripts));
+    }
+    for command in pre_commands {
+        openocd.arg("-c").arg(command);
+    }
+    openocd.arg("-f").arg(
+        desc.openocd_interface.clone().unwrap_or(PathBuf::from(DEFAULT_OPENOCD_INTERFACE))
+    );
+    if let Some(serial) = &desc.probe_serial {
+        openocd.arg("-c").arg(format!("adapter serial {}", serial));
+    }
+    if let Some(adapter_speed) = &desc.adapter_speed {
+        openocd.arg("-c").arg(format!("adapter speed {}", adapter_speed));
+    }
+    openocd.arg("-f").arg(
+        desc.openocd_target.clone().unwrap_or(PathBuf::from(chip(desc.mcu_type.as_ref()).openocd_target))
+    );
+    openocd
+}
+
+/// Opens an openocd session (see `server::Session`), connects to its TCL port and runs `session` on a halted target.
+/// A private openocd is shut down afterwards in any case. `what` describes the session for `--dry-run`.
+fn native_session(
+    desc: &FlashCmdDescriptor,
+    what: &str,
+    session: impl FnOnce(&mut TclClient, &ihex::HexImage) -> Result<(), RunError>,
+) -> Result<(), RunError> {
+    let app_hex_path = app_hex_path(desc)?;
+    let image = match desc.dry_run {
+        true => None,
+        false => Some(
+            ihex::read_file(&app_hex_path)
+                .map_err(|e| RunError::BadImage { path: app_hex_path.clone(), reason: e.to_string() })?,
+        ),
+    };
+
+    let openocd = Session::open(desc, false)?;
+    let Some(image) = image else {
+        events::dry_run(
+            "openocd tcl",
+            &format!(
+                "connect to {}:{}, reset halt, {}{}",
+                openocd.host,
+                openocd.ports.tcl,
+                what,
+                if openocd.external { "" } else { ", shutdown" }
+            ),
+        );
+        return Ok(());
+    };
+
+    let result = openocd.tcl().and_then(|mut client| match client.reset_halt() {
+        Ok(_) => session(&mut client, &image),
+        Err(e) => Err(RunError::ProbeNotFound { reason: format!("failed to halt target, {}", e), stderr: String::new() }),
+    });
+    let stderr = openocd.close();
+    result.map_err(|e| match e {
+        RunError::ProbeNotFound { reason, .. } => RunError::ProbeNotFound { reason, stderr },
+        RunError::UploadFailed { tool, reason, .. } => RunError::UploadFailed { tool, reason, stderr },
+        e => e,
+    })
+}
+
+/// Runs `program` in a native session and resets the target into the new firmware.
+fn native_upload(
+    desc: &FlashCmdDescriptor,
+    what: &str,
+    program: impl FnOnce(&mut TclClient, &[ihex::Segment]) -> Result<(), RunError>,
+) -> Result<(), RunError> {
+    native_session(desc, &format!("{}, reset run", what), |client, image| {
+        program(client, &image.segments)?;
+        client.reset_run().map_err(|e| native_failed(format!("failed to reset target, {}", e)))
+    })
+}
+
+fn native_failed(reason: String) -> RunError {
+    RunError::UploadFailed { tool: "openocd", reason, stderr: String::new() }
+}
+
+/// Loads a RAM image over openocd TCL port and starts it from the image entry with the stack at the top of RAM.
+/// Neither EEPROM nor SPIFI is touched, and no reset happens afterwards, since it would lose RAM contents.
+pub fn run_in_ram(desc: &FlashCmdDescriptor) -> Result<(), RunError> {
+    native_session(desc, "write image to RAM, set sp and pc, resume", |client, image| {
+        let ram = &chip(desc.mcu_type.as_ref()).ram;
+        let entry = image.start_address.unwrap_or(ram.start);
+        info!("Loading binary to RAM...");
+        let result = image.segments
+            .iter()
+            .try_for_each(|segment| write_segment(client, segment))
+            .and_then(|_| client.write_register("sp", ram.end()))
+            .and_then(|_| client.write_register("pc", entry))
+            .and_then(|_| client.resume());
+        match result {
+            Ok(()) => {
+                info!("Application started from RAM at 0x{:08x}", entry);
+                Ok(())
+            }
+            Err(e) => Err(native_failed(format!("failed to run application from RAM, {}", e))),
+        }
+    })
+}
+
+/// Writes a segment word by word when it is aligned, byte by byte otherwise.
+/// The last word is zero-padded, the following segment, if any, is written after it.
+fn write_segment(client: &mut TclClient, segment: &ihex::Segment) -> Result<(), TclError> {
+    if !segment.address.is_multiple_of(4) {
+        let bytes: Vec<u32> = segment.data.iter().map(|&b| b as u32).collect();
+        return client.write_memory(segment.address, 8, &bytes);
+    }
+    client.write_memory(segment.address, 32, &ihex::to_words(&segment.data))
+}
+
+/// Page progress of native uploads: a progress bar on the console, or `upload-progress` events
+/// for every new percent with `--message-format json`.
+struct Progress {
+    bar: indicatif::ProgressBar,
+    percent: Option<usize>,
+}
+
+impl Progress {
+    fn new() -> Self {
+        let bar = if logger::console_shows_info() && !events::json() {
+            indicatif::ProgressBar::new(0)
+        } else {
+            indicatif::ProgressBar::hidden()
+        };
+        Progress { bar, percent: None }
+    }
+
+    fn update(&mut self, done: usize, total: usize) {
+        self.bar.set_length(total as u64);
+        self.bar.set_position(done as u64);
+        let percent = done * 100 / total.max(1);
+        if self.percent != Some(percent) {
+            self.percent = Some(percent);
+            events::emit(Event::UploadProgress { done, total });
+        }
+    }
+
+    fn finish(self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// Native backends read back every page they write, so verification result comes with the upload result.
+fn emit_verify(verify_failed: Option<String>) {
+    events::emit(Event::Verify { success: verify_failed.is_none(), message: verify_failed });
+}
+
+/// Programs on-chip EEPROM without mik32_upload.py.
+fn upload_eeprom(desc: &FlashCmdDescriptor) -> Result<(), RunError> {
+    native_upload(desc, "program and verify EEPROM pages", |client, segments| {
+        info!("Uploading binary to EEPROM...");
+        let mut progress = Progress::new();
+        let region = &chip(desc.mcu_type.as_ref()).eeprom;
+        let result = eeprom::program(client, region, segments, |done, total| progress.update(done, total));
+        progress.finish();
+        match result {
+            Ok(()) => {
+                emit_verify(None);
+                info!("Application uploaded successfully");
+                Ok(())
+            }
+            Err(e) => {
+                if let eeprom::EepromError::VerifyFailed(_) = e {
+                    emit_verify(Some(e.to_string()));
+                }
+                Err(native_failed(format!("failed to program EEPROM, {}", e)))
+            }
+        }
+    })
+}
+
+/// Programs external flash through SPIFI without mik32_upload.py.
+fn upload_spifi(desc: &FlashCmdDescriptor) -> Result<(), RunError> {
+    native_upload(desc, "program and verify external flash pages", |client, segments| {
+        info!("Uploading binary to external flash{}...", if desc.use_quad_spi { " (QuadSPI)" } else { "" });
+        let mut progress = Progress::new();
+        let region = &chip(desc.mcu_type.as_ref()).spifi;
+        let result = spifi::program(client, region, segments, desc.use_quad_spi, |done, total| progress.update(done, total));
+        progress.finish();
+        match result {
+            Ok(chip) => {
+                info!(
+                    "Found {} flash (JEDEC ID {:02x}{:02x}{:02x}, {} KiB)",
+                    chip.vendor(),
+                    chip.jedec_id[0],
+                    chip.jedec_id[1],
+                    chip.jedec_id[2],
+                    chip.capacity / 1024
+                );
+                emit_verify(None);
+                info!("Application uploaded successfully");
+                Ok(())
+            }
+            Err(e) => {
+                if let spifi::SpifiError::VerifyFailed(_) = e {
+                    emit_verify(Some(e.to_string()));
+                }
+                Err(native_failed(format!("failed to program external flash, {}", e)))
+            }
+        }
+    })
+}
+
+/// Writes the image with openocd alone using `program`. With a background server running
+/// `program` goes over its TCL port, since a second openocd would not get the probe.
+/// An openocd on another host cannot be used, `program` there would look for the hex file on that host.
+fn upload_openocd(desc: &FlashCmdDescriptor) -> Result<(), RunError> {
+    if let Some(host) = desc.openocd_host.as_deref().filter(|host| !server::is_local(host)) {
+        return Err(RunError::BadArgument(format!(
+            "openocd backend cannot upload through openocd on {}, use --backend native or python",
+            host
+        )));
+    }
+    let app_hex_path = app_hex_path(desc)?;
+    let openocd_final_path = find_tool(Tool::Openocd, desc.openocd_path.as_deref(), &desc.project_dir)?;
+    if let Some(server) = Session::background(desc, &openocd_final_path)? {
+        return program_over_tcl(desc, &server, &app_hex_path);
+    }
+
+    let mut openocd = openocd_command(&openocd_final_path, desc, &Ports::disabled());
+    openocd.arg("-c").arg(format!("program {{{}}} verify reset exit", app_hex_path.display()));
+
+    if desc.dry_run {
+        events::dry_run("openocd", &process::describe(&openocd));
+        return Ok(());
+    }
+
+    info!("Uploading binary with openocd...");
+    run_upload(openocd, "openocd", Tool::Openocd, &openocd_final_path)?;
+    // `program` fails as a whole on a verify mismatch, success means the image was verified.
+    emit_verify(None);
+
+    info!("Application uploaded successfully");
+    Ok(())
+}
+
+/// Runs `program` of a running openocd. Unlike the standalone one it leaves openocd running.
+fn program_over_tcl(desc: &FlashCmdDescriptor, server: &Session, app_hex_path: &Path) -> Result<(), RunError> {
+    let command = format!("program {{{}}} verify reset", app_hex_path.display());
+    if desc.dry_run {
+        events::dry_run("openocd tcl", &format!("connect to {}:{}, {}", server.host, server.ports.tcl, command));
+        return Ok(());
+    }
+
+    info!("Uploading binary with openocd...");
+    server.tcl()?.execute(&command).map_err(|e| RunError::UploadFailed {
+        tool: "openocd",
+        reason: e.to_string(),
+        stderr: String::new(),
+    })?;
+    emit_verify(None);
+
+    info!("Application uploaded successfully");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn python_upload_to_remote_host_runs_no_local_openocd() {
+        let desc = FlashCmdDescriptor {
+            openocd_host: Some("10.0.0.5".to_owned()),
+            openocd_port: Some("7777".to_owned()),
+            boot_mode: Some(BootMode::Spifi),
+            ..Default::default()
+        };
+        let openocd = Session::open(&desc, false).unwrap();
+        let command = python_command(
+            &desc,
+            Path::new("/usr/bin/python3"),
+            Path::new("/opt/mik32-uploader"),
+            Path::new("/work/app.hex"),
+            &openocd,
+        )
+        .unwrap();
+        let args = args(&command);
+        assert!(!args.iter().any(|arg| arg == "--run-openocd" || arg == "--openocd-exec"));
+        assert!(args.windows(2).any(|pair| pair == ["--openocd-host", "10.0.0.5"]));
+        assert!(args.windows(2).any(|pair| pair == ["--openocd-port", "7777"]));
+        assert!(args.windows(2).any(|pair| pair == ["--boot-mode", "spifi"]));
+    }
+}
@@ -0,0 +1,356 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+const RECORD_DATA: u8 = 0x00;
+const RECORD_EOF: u8 = 0x01;
+const RECORD_EXT_SEGMENT: u8 = 0x02;
+const RECORD_START_SEGMENT: u8 = 0x03;
+const RECORD_EXT_LINEAR: u8 = 0x04;
+const RECORD_START_LINEAR: u8 = 0x05;
+
+const BYTES_PER_RECORD: usize = 16;
+pub const MAX_BINARY_SPAN: u32 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum HexError {
+    Io(std::io::Error),
+    BadRecord(usize),
+    BadChecksum(usize),
+    MissingEof,
+    Overlap(u32),
+    /// Segment at this address runs past the end of the 32-bit address space.
+    AddressOverflow(u32),
+}
+
+impl std::fmt::Display for HexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HexError::Io(e) => write!(f, "failed to access hex file: {}", e),
+            HexError::BadRecord(line) => write!(f, "malformed record at line {}", line),
+            HexError::BadChecksum(line) => write!(f, "checksum mismatch at line {}", line),
+            HexError::MissingEof => write!(f, "no end-of-file record"),
+            HexError::Overlap(address) => write!(f, "data at 0x{:08x} is defined more than once", address),
+            HexError::AddressOverflow(address) => write!(f, "data at 0x{:08x} runs past the end of the address space", address),
+        }
+    }
+}
+
+/// Continuous block of bytes starting at `address`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// Address right after the last byte. Segments of a `HexImage` are checked to end within the address space.
+    pub fn end(&self) -> u32 {
+        self.address + self.data.len() as u32
+    }
+}
+
+/// Contents of an Intel HEX file: sorted, non-overlapping segments and an optional entry point.
+#[derive(Default)]
+pub struct HexImage {
+    pub segments: Vec<Segment>,
+    pub start_address: Option<u32>,
+}
+
+impl HexImage {
+    /// Builds an image out of arbitrary segments, sorting and merging the adjacent ones.
+    pub fn from_segments(mut segments: Vec<Segment>, start_address: Option<u32>) -> Result<Self, HexError> {
+        segments.sort_by_key(|s| s.address);
+        let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+        for segment in segments.into_iter().filter(|s| !s.data.is_empty()) {
+            u32::try_from(segment.data.len())
+                .ok()
+                .and_then(|len| segment.address.checked_add(len))
+                .ok_or(HexError::AddressOverflow(segment.address))?;
+            match merged.last_mut() {
+                Some(last) if last.end() > segment.address => return Err(HexError::Overlap(segment.address)),
+                Some(last) if last.end() == segment.address => last.data.extend_from_slice(&segment.data),
+                _ => merged.push(segment),
+            }
+        }
+        Ok(HexImage { segments: merged, start_address })
+    }
+
+    /// Total amount of data bytes, gaps are not counted.
+    pub fn size(&self) -> usize {
+        self.segments.iter().map(|s| s.data.len()).sum()
+    }
+
+    /// Parts of the image that fall into `[start, end)`. Segments crossing the boundaries are cut.
+    pub fn segments_in(&self, start: u32, end: u32) -> Vec<Segment> {
+        self.segments
+            .iter()
+            .filter(|s| s.address < end && s.end() > start)
+            .map(|s| {
+                let from = s.address.max(start);
+                let to = s.end().min(end);
+                Segment {
+                    address: from,
+                    data: s.data[(from - s.address) as usize..(to - s.address) as usize].to_vec(),
+                }
+            })
+            .collect()
+    }
+
+    /// Flat binary from the lowest to the highest address, gaps filled with 0xFF.
+    /// Returns `None` when the image is spread over more than `MAX_BINARY_SPAN`, e.g. EEPROM plus SPIFI.
+    pub fn to_binary(&self) -> Option<Vec<u8>> {
+        let first = self.segments.first()?;
+        let last = self.segments.last()?;
+        if last.end() - first.address > MAX_BINARY_SPAN {
+            return None;
+        }
+        let mut binary = vec![0xFF; (last.end() - first.address) as usize];
+        for segment in &self.segments {
+            let at = (segment.address - first.address) as usize;
+            binary[at..at + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        Some(binary)
+    }
+
+    /// Serializes the image with extended linear address records and 16 bytes per data record.
+    pub fn to_hex_string(&self) -> String {
+        let mut out = String::new();
+        let mut upper: Option<u16> = None;
+        for segment in &self.segments {
+            let mut address = segment.address;
+            let mut rest = segment.data.as_slice();
+            while !rest.is_empty() {
+                let segment_upper = (address >> 16) as u16;
+                if upper != Some(segment_upper) {
+                    push_record(&mut out, RECORD_EXT_LINEAR, 0, &segment_upper.to_be_bytes());
+                    upper = Some(segment_upper);
+                }
+                // Records must not cross a 64 KiB boundary.
+                let to_boundary = 0x1_0000 - (address & 0xFFFF) as usize;
+                let len = rest.len().min(BYTES_PER_RECORD).min(to_boundary);
+                push_record(&mut out, RECORD_DATA, address as u16, &rest[..len]);
+                rest = &rest[len..];
+                address = address.wrapping_add(len as u32);
+            }
+        }
+        if let Some(start) = self.start_address {
+            push_record(&mut out, RECORD_START_LINEAR, 0, &start.to_be_bytes());
+        }
+        push_record(&mut out, RECORD_EOF, 0, &[]);
+        out
+    }
+
+    pub fn write_file(&self, path: &Path) -> Result<(), HexError> {
+        fs::write(path, self.to_hex_string()).map_err(HexError::Io)
+    }
+}
+
+pub fn read_file(path: &Path) -> Result<HexImage, HexError> {
+    let text = fs::read_to_string(path).map_err(HexError::Io)?;
+    parse(&text)
+}
+
+/// Parses all six record types and validates record checksums.
+/// Missing end-of-file record is an error, since it usually means the file was cut.
+pub fn parse(text: &str) -> Result<HexImage, HexError> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut start_address = None;
+    let mut base: u32 = 0;
+    let mut eof = false;
+
+    for (index, line) in text.lines().enumerate() {
+        let line_no = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bytes = line
+            .strip_prefix(':')
+            .and_then(decode_hex)
+            .ok_or(HexError::BadRecord(line_no))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(HexError::BadRecord(line_no));
+        }
+        if bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0 {
+            return Err(HexError::BadChecksum(line_no));
+        }
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..bytes.len() - 1];
+
+        match (bytes[3], data.len()) {
+            (RECORD_DATA, _) => {
+                let address = base.wrapping_add(offset);
+                address.checked_add(data.len() as u32).ok_or(HexError::BadRecord(line_no))?;
+                match segments.last_mut() {
+                    Some(last) if last.end() == address => last.data.extend_from_slice(data),
+                    _ => segments.push(Segment { address, data: data.to_vec() }),
+                }
+            }
+            (RECORD_EOF, 0) => {
+                eof = true;
+                break;
+            }
+            (RECORD_EXT_SEGMENT, 2) => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            (RECORD_EXT_LINEAR, 2) => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            (RECORD_START_SEGMENT, 4) => {
+                let cs = u16::from_be_bytes([data[0], data[1]]) as u32;
+                let ip = u16::from_be_bytes([data[2], data[3]]) as u32;
+                start_address = Some((cs << 4) + ip);
+            }
+            (RECORD_START_LINEAR, 4) => start_address = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]])),
+            _ => return Err(HexError::BadRecord(line_no)),
+        }
+    }
+    if !eof {
+        return Err(HexError::MissingEof);
+    }
+    HexImage::from_segments(segments, start_address)
+}
+
+/// Spreads segments over pages of `page_size` bytes, keyed by page offset from `start`.
+/// Bytes of a touched page not covered by any segment are set to `fill`.
+/// Fails with the first address outside of `[start, start + size)`.
+pub fn split_into_pages(segments: &[Segment], start: u32, size: u32, page_size: u32, fill: u8) -> Result<BTreeMap<u32, Vec<u8>>, u32> {
+    let mut pages: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+    for segment in segments {
+        for (i, byte) in segment.data.iter().enumerate() {
+            let address = segment.address.wrapping_add(i as u32);
+            let offset = address.wrapping_sub(start);
+            if offset >= size {
+                return Err(address);
+            }
+            let page = pages
+                .entry(offset - offset % page_size)
+                .or_insert_with(|| vec![fill; page_size as usize]);
+            page[(offset % page_size) as usize] = *byte;
+        }
+    }
+    Ok(pages)
+}
+
+/// Little-endian words of `bytes`, the last one zero-padded.
+pub fn to_words(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks(4)
+        .map(|chunk| {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u32::from_le_bytes(word)
+        })
+        .collect()
+}
+
+fn push_record(out: &mut String, record_type: u8, offset: u16, data: &[u8]) {
+    let mut bytes = Vec::with_capacity(data.len() + 5);
+    bytes.push(data.len() as u8);
+    bytes.extend_from_slice(&offset.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_sub(*b));
+    bytes.push(checksum);
+
+    out.push(':');
+    for byte in bytes {
+        out.push_str(&format!("{:02X}", byte));
+    }
+    out.push('\n');
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(record_type: u8, offset: u16, data: &[u8]) -> String {
+        let mut out = String::new();
+        push_record(&mut out, record_type, offset, data);
+        out
+    }
+
+    fn eof() -> String {
+        record(RECORD_EOF, 0, &[])
+    }
+
+    #[test]
+    fn bad_checksum_is_rejected() {
+        let mut text = record(RECORD_DATA, 0, &[1, 2, 3, 4]);
+        let checksum = text.len() - 3;
+        text.replace_range(checksum.., "00\n");
+        text.push_str(&eof());
+        assert!(matches!(parse(&text), Err(HexError::BadChecksum(1))));
+    }
+
+    #[test]
+    fn extended_segment_address_is_shifted_by_four() {
+        let text = record(RECORD_EXT_SEGMENT, 0, &[0x10, 0x00]) + &record(RECORD_DATA, 0x0010, &[0xAA, 0xBB]) + &eof();
+        let image = parse(&text).unwrap();
+        assert_eq!(image.segments, vec![Segment { address: 0x0001_0010, data: vec![0xAA, 0xBB] }]);
+    }
+
+    #[test]
+    fn extended_linear_address_is_shifted_by_sixteen() {
+        let text = record(RECORD_EXT_LINEAR, 0, &[0x80, 0x00])
+            + &record(RECORD_DATA, 0x0100, &[1, 2])
+            + &record(RECORD_EXT_LINEAR, 0, &[0x01, 0x00])
+            + &record(RECORD_DATA, 0x0000, &[3])
+            + &eof();
+        let image = parse(&text).unwrap();
+        assert_eq!(
+            image.segments,
+            vec![Segment { address: 0x0100_0000, data: vec![3] }, Segment { address: 0x8000_0100, data: vec![1, 2] }]
+        );
+    }
+
+    #[test]
+    fn missing_eof_is_rejected() {
+        let text = record(RECORD_DATA, 0, &[1, 2, 3, 4]);
+        assert!(matches!(parse(&text), Err(HexError::MissingEof)));
+    }
+
+    #[test]
+    fn overlapping_records_are_rejected() {
+        let text = record(RECORD_DATA, 0x0000, &[1, 2, 3, 4]) + &record(RECORD_DATA, 0x0002, &[5, 6]) + &eof();
+        assert!(matches!(parse(&text), Err(HexError::Overlap(0x0002))));
+    }
+
+    #[test]
+    fn record_past_the_address_space_is_rejected() {
+        let text = record(RECORD_EXT_LINEAR, 0, &[0xFF, 0xFF]) + &record(RECORD_DATA, 0xFFF0, &[0; 16]) + &eof();
+        assert!(matches!(parse(&text), Err(HexError::BadRecord(2))));
+
+        let segments = vec![Segment { address: 0xFFFF_FFF0, data: vec![0; 16] }];
+        assert!(matches!(HexImage::from_segments(segments, None), Err(HexError::AddressOverflow(0xFFFF_FFF0))));
+    }
+
+    #[test]
+    fn hex_string_round_trips() {
+        let segments = vec![
+            Segment { address: 0x0100_0000, data: (0..40).collect() },
+            // Crosses the 64 KiB boundary at 0x80010000 in the middle of a record.
+            Segment { address: 0x8000_FFF8, data: (0..=255).collect() },
+        ];
+        let image = HexImage::from_segments(segments.clone(), Some(0x8000_0000)).unwrap();
+        let text = image.to_hex_string();
+
+        for line in text.lines() {
+            let bytes = decode_hex(&line[1..]).unwrap();
+            if bytes[3] == RECORD_DATA {
+                let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+                assert!(offset + bytes[0] as usize <= 0x1_0000, "record crosses 64 KiB boundary: {}", line);
+            }
+        }
+        assert!(text.contains(&record(RECORD_EXT_LINEAR, 0, &[0x80, 0x01])));
+
+        let parsed = parse(&text).unwrap();
+        assert_eq!(parsed.segments, segments);
+        assert_eq!(parsed.start_address, Some(0x8000_0000));
+    }
+}
@@ -1,18 +1,60 @@
 use std::env;
-use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use std::fs;
 
 use std::process::{Command, Stdio};
 
+use clap::ValueEnum;
+use log::{info, warn};
+
+use crate::chip::{chip, Chip, DEFAULT_SPIFI_FLASH_KIB};
+use crate::config::LOCAL_CONFIG_FILE;
+use crate::{events, logger};
+use crate::process::{StderrTail, Supervised};
+use crate::{exit_code, BootMode, MCUType};
+
 #[derive(Debug)]
 pub enum InitError {
-    BadName,
-    FetchFailed,
+    /// Directory with the project name already exists.
+    BadName(PathBuf),
+    FetchFailed { dependency: String, reason: String, stderr: String },
+    Io { path: PathBuf, error: std::io::Error },
+}
+
+impl InitError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            InitError::BadName(_) => exit_code::USAGE,
+            InitError::FetchFailed { .. } => exit_code::FETCH_FAILED,
+            InitError::Io { .. } => exit_code::IO,
+        }
+    }
+}
+
+impl std::fmt::Display for InitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InitError::BadName(dir) => write!(f, "{} already exists, name the project differently", dir.display()),
+            InitError::FetchFailed { dependency, reason, stderr } => {
+                write!(f, "failed to add dependency {}, {}", dependency, reason)?;
+                if !stderr.is_empty() {
+                    write!(f, "\ncargo stderr (last lines):\n{}", stderr)?;
+                }
+                Ok(())
+            }
+            InitError::Io { path, error } => write!(f, "failed to create {}, {}", path.display(), error),
+        }
+    }
 }
 
-pub fn make_project(name: String, project_dir: PathBuf) -> Result<(), InitError>{
+pub fn make_project(
+    name: String,
+    project_dir: PathBuf,
+    boot_mode: Option<BootMode>,
+    mcu_type: Option<MCUType>,
+    flash_size: Option<u32>,
+) -> Result<(), InitError>{
     
     let project_dir = project_dir.join(name.clone());
 
@@ -24,24 +66,31 @@ pub fn make_project(name: String, project_dir: PathBuf) -> Result<(), InitError>
             .template("{spinner:.green} {msg}")
             .unwrap(),
     );
+    if !logger::console_shows_info() || events::json() {
+        pb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+    }
     
 
     if project_dir.exists() {
-        eprintln!("Project with same name exists. Make sure to name it differently.");
-        return Err(InitError::BadName);
+        return Err(InitError::BadName(project_dir));
     }
 
+    let boot_mode = match boot_mode {
+        Some(BootMode::Undefined) | None => BootMode::Eeprom,
+        Some(mode) => mode,
+    };
+    let boot_mode_name = boot_mode.to_possible_value().unwrap().get_name().to_owned();
+    let mcu_type_name = mcu_type.clone().unwrap_or(MCUType::MIK32V2).to_possible_value().unwrap().get_name().to_owned();
+
     pb.set_message(message.clone() + "Creating structure");
     pb.enable_steady_tick(Duration::from_millis(100));
 
-    fs::create_dir_all(project_dir.join("src")).expect("Failed to make src directory in the project.");
-    fs::create_dir_all(project_dir.join(".cargo")).expect("Failed to make .cargo directory in the project.");
-    fs::create_dir_all(project_dir.join("flash")).expect("Failed to create flash directory in the project.");
-    let mut cargo_toml = fs::File::create(project_dir.join("Cargo.toml")).expect("Failed to create Cargo.toml in the project.");
-    let mut cargo_config = fs::File::create(project_dir.join(".cargo").join("config.toml")).expect("Failed to create ./.cargo/config.toml in the project.");
-    let mut main_rs = fs::File::create(project_dir.join("src").join("main.rs")).expect("Failed to create ./src/main.rs in the project.");
+    for dir in ["src", ".cargo", "flash"] {
+        let dir = project_dir.join(dir);
+        fs::create_dir_all(&dir).map_err(|error| InitError::Io { path: dir, error })?;
+    }
 
-    let _ = cargo_toml.write_all(format!(
+    write_file(&project_dir.join("Cargo.toml"), &format!(
         r#"
         [package]
         name = "{name}"
@@ -54,20 +103,24 @@ pub fn make_project(name: String, project_dir: PathBuf) -> Result<(), InitError>
         opt-level = "z"
         lto = true
         codegen-units = 1
+
+        # Defaults for 'cargo mik32 run'. Personal overrides go to .mik32.local.toml.
+        [package.metadata.mik32]
+        boot-mode = "{boot_mode_name}"
+        mcu-type = "{mcu_type_name}"
         "# 
-        ).as_bytes()
-    ).unwrap();
+    ))?;
 
-    let _ = cargo_config.write_all(r#"
+    write_file(&project_dir.join(".cargo").join("config.toml"), r#"
         [target.riscv32imc-unknown-none-elf]
         rustflags = ["-C", "link-arg=-Tlink.x"]
 
         [build]
         target = "riscv32imc-unknown-none-elf"
-        "#.as_bytes()
-    );
+        "#
+    )?;
 
-    let _ = main_rs.write_all(r#"
+    write_file(&project_dir.join("src").join("main.rs"), r#"
         #![no_std]
         #![no_main]
 
@@ -77,8 +130,27 @@ pub fn make_project(name: String, project_dir: PathBuf) -> Result<(), InitError>
         fn main() -> ! {
             loop {}
         }
-        "#.as_bytes()
-    );      
+        "#
+    )?;
+
+    let flash_size = flash_size.unwrap_or(DEFAULT_SPIFI_FLASH_KIB);
+    write_file(&project_dir.join("memory.x"), &memory_layout(chip(mcu_type.as_ref()), &boot_mode, flash_size))?;
+
+    write_file(&project_dir.join("build.rs"), r#"
+        use std::{env, fs, path::PathBuf};
+
+        // Puts memory.x on the linker search path, so link.x of mik32-rt can include it.
+        fn main() {
+            let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+            fs::copy("memory.x", out.join("memory.x")).unwrap();
+            println!("cargo:rustc-link-search={}", out.display());
+            println!("cargo:rerun-if-changed=memory.x");
+            println!("cargo:rerun-if-changed=build.rs");
+        }
+        "#
+    )?;
+
+    write_file(&project_dir.join(".gitignore"), &format!("/target\n/{}\n", LOCAL_CONFIG_FILE))?;
 
     pb.set_message(message.clone() + "Adding dependencies");
     cargo_add(&project_dir, "https://github.com/mik32-rs/mik32-hal.git".to_owned(), true)?;
@@ -89,14 +161,14 @@ pub fn make_project(name: String, project_dir: PathBuf) -> Result<(), InitError>
     match env::var("MIK32_UPLOADER_PATH") {
         Ok(_) => (),
         Err(_) => {
-            eprintln!("WARN: Ensure you provide mik32-uploader path in MIK32_UPLOADER_PATH environment variable");
+            warn!("Ensure you provide mik32-uploader path in MIK32_UPLOADER_PATH environment variable");
         }
     }
 
     match env::var("MIK32_OPENOCD_PATH") {
         Ok(_) => (),
         Err(_) => {
-            eprintln!("WARN: Ensure you provide openocd path in MIK32_OPENOCD_PATH environment variable");
+            warn!("Ensure you provide openocd path in MIK32_OPENOCD_PATH environment variable");
         }
     }
 
@@ -112,11 +184,11 @@ pub fn make_project(name: String, project_dir: PathBuf) -> Result<(), InitError>
     match git_cmd {
         Ok(stat) => {
             if !stat.success() {
-                eprintln!("Failed to initialize git repo.");
+                warn!("Failed to initialize git repo.");
             }
         }
         Err(_) => {
-            eprintln!("Failed to run git init.")
+            warn!("Failed to run git init.")
         }
     }
 
@@ -124,9 +196,42 @@ pub fn make_project(name: String, project_dir: PathBuf) -> Result<(), InitError>
     Ok(())
 }
 
+fn write_file(path: &Path, contents: &str) -> Result<(), InitError> {
+    fs::write(path, contents).map_err(|error| InitError::Io { path: path.to_path_buf(), error })
+}
+
+/// memory.x for the chip: all memories are declared and code is placed with region aliases
+/// into the memory the chip boots from. Data, heap and stack always live in RAM.
+/// SPIFI is declared with the size of the flash chip in KiB rather than its whole address window,
+/// so an image too big for the chip fails to link.
+fn memory_layout(chip: &Chip, boot_mode: &BootMode, flash_size: u32) -> String {
+    let code = match boot_mode {
+        BootMode::Ram => chip.ram.name,
+        BootMode::Spifi => chip.spifi.name,
+        BootMode::Eeprom | BootMode::Undefined => chip.eeprom.name,
+    };
+    let mut layout = format!("/* {} */\nMEMORY\n{{\n", chip.name);
+    for region in chip.regions() {
+        let size = if region.name == chip.spifi.name { flash_size } else { region.size / 1024 };
+        layout.push_str(&format!("    {} : ORIGIN = 0x{:08x}, LENGTH = {}K\n", region.name, region.start, size));
+    }
+    layout.push_str("    /* SPIFI LENGTH is the external flash size, change it to match the flash chip on the board. */\n");
+    layout.push_str("}\n\n");
+    for (alias, region) in [
+        ("REGION_TEXT", code),
+        ("REGION_RODATA", code),
+        ("REGION_DATA", chip.ram.name),
+        ("REGION_BSS", chip.ram.name),
+        ("REGION_HEAP", chip.ram.name),
+        ("REGION_STACK", chip.ram.name),
+    ] {
+        layout.push_str(&format!("REGION_ALIAS(\"{}\", {});\n", alias, region));
+    }
+    layout
+}
 
 #[inline(always)]
-fn cargo_add(project_dir: &PathBuf, dependency: String, git: bool) -> Result<(), InitError> {
+fn cargo_add(project_dir: &Path, dependency: String, git: bool) -> Result<(), InitError> {
     let mut cargo_cmd = Command::new("cargo");
     cargo_cmd.arg("add");
     if git {
@@ -136,24 +241,37 @@ fn cargo_add(project_dir: &PathBuf, dependency: String, git: bool) -> Result<(),
     cargo_cmd.arg("--manifest-path");
     cargo_cmd.arg(project_dir.join("Cargo.toml"));
 
-    let cargo_stat = cargo_cmd
-        //.stdout(Stdio::null())
-        //.stderr(Stdio::null())
-        .status();
-
-    match cargo_stat {
-        Ok(stat) => {
-            if !stat.success() {
-                eprintln!("Failed to add dependency {}", dependency);
-                return Err(InitError::FetchFailed);
-            } else {
-                println!("Added dependency: {}", dependency);
-            }
-        }
-        Err(err) => {
-            eprintln!("Failed to run cargo-add on dependency {}, {}", dependency, err);
-            return Err(InitError::FetchFailed);
+    let fetch_failed = |reason: String, stderr: String| InitError::FetchFailed { dependency: dependency.clone(), reason, stderr };
+    let mut child = Supervised::spawn(cargo_cmd.stderr(Stdio::piped()))
+        .map_err(|e| fetch_failed(format!("failed to run cargo add, {}", e), String::new()))?;
+    // cargo add is quiet on success, its stderr is only shown when it fails.
+    let stderr = StderrTail::capture(&mut child, "cargo", false);
+    let status = child.wait();
+    let stderr = stderr.finish();
+    match status {
+        Ok(stat) if stat.success() => {
+            info!("Added dependency: {}", dependency);
+            Ok(())
         }
+        Ok(stat) => Err(fetch_failed(format!("cargo add exited with {}", stat), stderr)),
+        Err(err) => Err(fetch_failed(err.to_string(), stderr)),
     }
-    Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spifi_is_declared_with_flash_size() {
+        let layout = memory_layout(chip(None), &BootMode::Spifi, DEFAULT_SPIFI_FLASH_KIB);
+        assert!(layout.contains("EEPROM : ORIGIN = 0x01000000, LENGTH = 8K\n"), "{}", layout);
+        assert!(layout.contains("RAM : ORIGIN = 0x02000000, LENGTH = 16K\n"), "{}", layout);
+        assert!(layout.contains("SPIFI : ORIGIN = 0x80000000, LENGTH = 8192K\n"), "{}", layout);
+        assert!(layout.contains("REGION_ALIAS(\"REGION_TEXT\", SPIFI);"), "{}", layout);
+
+        let layout = memory_layout(chip(None), &BootMode::Eeprom, 2048);
+        assert!(layout.contains("SPIFI : ORIGIN = 0x80000000, LENGTH = 2048K\n"), "{}", layout);
+        assert!(layout.contains("REGION_ALIAS(\"REGION_TEXT\", EEPROM);"), "{}", layout);
+    }
+}
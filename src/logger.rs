@@ -0,0 +1,78 @@
+use std::{fs::File, io::Write, path::Path, sync::{Mutex, OnceLock}, time::SystemTime};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Console logger on stderr, plus an optional log file with timestamps that also receives
+/// subprocess output. The file always gets at least debug records, whatever the console level is.
+struct Logger {
+    console: LevelFilter,
+    file: Option<(LevelFilter, Mutex<File>)>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// `verbosity` is the number of `-v` flags, or -1 for `-q`.
+pub fn init(verbosity: i8, log_file: Option<&Path>) -> std::io::Result<()> {
+    let console = match verbosity {
+        ..0 => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let file = match log_file {
+        Some(path) => Some((console.max(LevelFilter::Debug), Mutex::new(File::create(path)?))),
+        None => None,
+    };
+    let max = file.as_ref().map_or(console, |(level, _)| console.max(*level));
+    let logger = LOGGER.get_or_init(|| Logger { console, file });
+    // Only fails when a logger is already set, which is fine.
+    let _ = log::set_logger(logger);
+    log::set_max_level(max);
+    Ok(())
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.console
+            || self.file.as_ref().is_some_and(|(level, _)| metadata.level() <= *level)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= self.console {
+            match record.level() {
+                Level::Error => eprintln!("error: {}", record.args()),
+                Level::Warn => eprintln!("warning: {}", record.args()),
+                Level::Info => eprintln!("{}", record.args()),
+                Level::Debug | Level::Trace => eprintln!("[{}] {}", record.level().as_str().to_lowercase(), record.args()),
+            }
+        }
+        if let Some((level, file)) = &self.file
+            && record.level() <= *level
+        {
+            write_line(file, record.level().as_str(), &record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some((_, file)) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Writes a line of subprocess output to the log file, if there is one. Console echo is up to the caller.
+pub fn subprocess(tool: &str, line: &str) {
+    if let Some(Logger { file: Some((_, file)), .. }) = LOGGER.get() {
+        write_line(file, tool, line);
+    }
+}
+
+/// Echoed subprocess output is only shown when info records reach the console.
+pub fn console_shows_info() -> bool {
+    LOGGER.get().is_none_or(|logger| logger.console >= LevelFilter::Info)
+}
+
+fn write_line(file: &Mutex<File>, tag: &str, line: &str) {
+    let timestamp = humantime::format_rfc3339_millis(SystemTime::now());
+    let _ = writeln!(file.lock().unwrap(), "{} {:<7} {}", timestamp, tag, line);
+}
@@ -5,59 +5,177 @@ use crate::{build_script::run_wrapper};
 
 
 mod build_script;
+mod chip;
+mod config;
+mod doctor;
+mod eeprom;
+mod elf;
+mod events;
+mod exit_code;
+mod flasher;
+mod ihex;
 mod init_script;
+mod logger;
+mod probe_lock;
+mod process;
+mod server;
+mod spifi;
+mod tcl_client;
+mod tools;
 
 #[derive(Parser)]
+#[command(after_help = exit_code::HELP)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, help="More output. '-v' shows resolved tools and executed commands, '-vv' also openocd TCL traffic.")]
+    verbose: u8,
+    #[arg(short, long, global = true, conflicts_with = "verbose", help="Only print warnings and errors.")]
+    quiet: bool,
+    #[arg(long, global = true, help="Write a timestamped trace, including output of cargo, uploader, openocd and gdb, to this file.")]
+    log_file: Option<PathBuf>,
+    #[arg(long, global = true, default_value = "human", help="Output format. 'json' prints one JSON event per pipeline step to stdout, human readable output stays on stderr.")]
+    message_format: MessageFormat,
 }
 
 
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     Init {
         name: String,
-        //#[arg(long, help="Pass a chip model. Not yet implemented.")]
-        //chip: Option<String>,
+        #[arg(short, long, help="Memory the application is linked for. Decides where memory.x places code. 'eeprom' by default.")]
+        boot_mode: Option<BootMode>,
+        #[arg(short, long, help="MCU type selection. Defines memory map written to memory.x. MIK32V2 by default.")]
+        mcu_type: Option<MCUType>,
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..=262144), help="Size of the external SPIFI flash in KiB, written to memory.x. 8192 (8 MiB) by default. Can be changed in memory.x later.")]
+        flash_size: Option<u32>,
+    },
+    /// Check toolchain, uploader and openocd setup.
+    Doctor {
+        #[arg(long, help="Apply safe fixes, like installing the rustup target.")]
+        fix: bool,
+    },
+    /// Inspect project configuration.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Manage a background openocd that 'run' reuses instead of starting its own.
+    Server {
+        #[command(subcommand)]
+        command: ServerCommand,
     },
     Run {
         #[arg(short, long, help="Pass an example. Will upload example application. Rebuilding is necessary. If you provide 'reuse' flag will skip objcopy therefore will not build example.")]
         example: Option<String>,
+        #[arg(long, help="Pass a binary target of the package to build and upload.")]
+        bin: Option<String>,
+        #[arg(short, long, help="Package to build in a workspace.")]
+        package: Option<String>,
+        #[arg(long, help="Build profile. 'release' by default.")]
+        profile: Option<String>,
+        #[arg(short = 'F', long, help="Features to activate. May be repeated.")]
+        features: Vec<String>,
+        #[arg(long, help="Do not activate the 'default' feature.")]
+        no_default_features: bool,
+        #[arg(long, help="Directory for build artifacts. CARGO_TARGET_DIR is honored when not provided.")]
+        target_dir: Option<PathBuf>,
         #[arg(long, help="Reuse flag. If and only if app-hex-path was provided will skip objcopy, check binary existance and perform upload.")]
         reuse: bool,
-        #[arg(short, long, help="Pass a gdb executable. If provided will try to connect to a board with internal gdb script.")]
+        #[arg(long, overrides_with = "no_wait", help="Wait for the probe when another run uses it. Fails naming the process that holds it otherwise.")]
+        wait: bool,
+        #[arg(long, overrides_with = "wait", help="Fail right away when another run uses the probe. The default.")]
+        no_wait: bool,
+        #[arg(long, help="Resolve tools and validate arguments and image, then print cargo, uploader, openocd and gdb command lines and the GDB script instead of running them.")]
+        dry_run: bool,
+        #[arg(long, help="Connect gdb to the board with internal gdb script after upload. gdb is searched in --gdb-exec, project config, MIK32_GDB_EXEC and PATH in that order.")]
+        gdb: bool,
+        #[arg(short, long, help="Pass a gdb executable. Implies --gdb.")]
         gdb_exec: Option<String>,
-        #[arg(long)]
+        #[arg(long, help="Pass an ELF for gdb manually. By default the ELF built by cargo is used.")]
         gdb_target_path: Option<PathBuf>,
-        #[arg(short, long, help="Pass an openocd path. Otherwise will seek in MIK32_OPENOCD_PATH environment variable and then using 'which openocd' command.")]
+        #[arg(short, long, help="Pass an openocd path. Otherwise project config, MIK32_OPENOCD_PATH, ./flash/openocd/bin/openocd and PATH are searched in that order.")]
         openocd_path: Option<PathBuf>,
-        #[arg(short, long, help="Pass a uploader path manually. Otherwise will seek in MIK32_UPLOADER_PATH environment variable and then in project directory.")]
+        #[arg(short, long, help="Pass a uploader path manually. Otherwise project config, MIK32_UPLOADER_PATH and ./flash/mik32-uploader are searched in that order.")]
         uploader_path: Option<PathBuf>,
         #[arg(short, long, help="Pass a hex binary manually. Will skip objcopy step and upload application immideatly. Otherwise will automatically rebuild app.")]
         app_hex_path: Option<PathBuf>,
+        #[arg(long, help="Select how hex image is produced: 'native' reads the built ELF directly, 'cargo' uses cargo-objcopy from cargo-binutils. 'native' by default.")]
+        objcopy: Option<ObjcopyTool>,
 
         //All essential uploader arguments are passed.
-        #[arg(long, help="Direct argument pass from uploader. Use QuadSPI mode while programming external flash memory.")]
+        #[arg(long, help="Use QuadSPI mode while programming external flash memory.")]
         use_quad_spi: bool,
-        #[arg(long, help="Direct argument pass from uploader. Connection address to openocd server. 127.0.0.1 by default")]
+        #[arg(long, help="Direct argument pass from uploader. Connection address to openocd server. 127.0.0.1 by default. For another host no local openocd is started: native and python uploads and gdb use the one running there, the openocd backend is refused since that openocd cannot read the local hex file.")]
         openocd_host: Option<String>,
         #[arg(long, help="Direct argument pass from uploader. Port of tcl openocd server. 6666 by default.")]
         openocd_port: Option<String>,
+        #[arg(long, help="Port of openocd gdb server, used as given. Without it a local openocd gets 3333, or a free port when 3333 is taken.")]
+        gdb_port: Option<String>,
         #[arg(long, help="Direct argument pass from uploader. Speed of debugger in kHz. 500 bu default")]
         adapter_speed: Option<String>,
+        #[arg(long, help="Probe profile from user config (~/.config/cargo-mik32/config.toml). Fills interface, adapter speed, host, ports and serial not given on the command line or in MIK32_* environment variables. Overrides the project config.")]
+        probe: Option<String>,
         #[arg(long, help="Pass openocd scripts manually. Will ignore default location of 'scripts' directory and use provided instead.")]
         openocd_scripts: Option<PathBuf>,
         #[arg(long, help="Direct argument pass from uploader. Path to configuration file of debugger relative to 'scripts' path. 'interface/ftdi/m-link.cfg' by default")]
         openocd_interface: Option<PathBuf>,
         #[arg(long, help="Direct argument pass from uploader. Path to configuration file of target MCU relative to 'scripts' path. 'target/mik32.cfg' by default")]
         openocd_target: Option<PathBuf>,
-        #[arg(short, long, help="Select memory type. Not yet implemented.")]
+        #[arg(long, help="Select upload backend: 'python' runs mik32_upload.py, 'native' talks to openocd TCL port directly, 'openocd' uses openocd 'program'. By default EEPROM and SPIFI use 'native', everything else 'python'. Not used with '--boot-mode ram', which loads the image straight into RAM.")]
+        backend: Option<Backend>,
+        #[arg(short, long, help="Select memory type. Image addresses are checked against it and upload backend and gdb session are picked accordingly. Inferred from the image when omitted or 'undefined'.")]
         boot_mode: Option<BootMode>,
-        #[arg(short, long, help="MCU type selection. Not yet implemented.")]
+        #[arg(short, long, help="MCU type selection. Defines memory map used for upload and gdb, and default openocd target. MIK32V2 by default.")]
+        mcu_type: Option<MCUType>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print every option resolved from [package.metadata.mik32], .mik32.local.toml and environment, with its source.
+    Show,
+}
+
+#[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
+enum ServerCommand {
+    /// Start openocd in the background and wait until it accepts connections.
+    Start {
+        #[arg(short, long, help="Pass an openocd path. Otherwise project config, MIK32_OPENOCD_PATH, ./flash/openocd/bin/openocd and PATH are searched in that order.")]
+        openocd_path: Option<PathBuf>,
+        #[arg(long, help="Connection address 'run' uses to reach the server. 127.0.0.1 by default.")]
+        openocd_host: Option<String>,
+        #[arg(long, help="Port of tcl openocd server. 6666 by default.")]
+        openocd_port: Option<String>,
+        #[arg(long, help="Port of openocd gdb server. 3333 by default.")]
+        gdb_port: Option<String>,
+        #[arg(long, help="Speed of debugger in kHz. 500 by default.")]
+        adapter_speed: Option<String>,
+        #[arg(long, help="Probe profile from user config (~/.config/cargo-mik32/config.toml).")]
+        probe: Option<String>,
+        #[arg(long, help="Pass openocd scripts manually. Will ignore default location of 'scripts' directory and use provided instead.")]
+        openocd_scripts: Option<PathBuf>,
+        #[arg(long, help="Path to configuration file of debugger relative to 'scripts' path. 'interface/ftdi/m-link.cfg' by default.")]
+        openocd_interface: Option<PathBuf>,
+        #[arg(long, help="Path to configuration file of target MCU relative to 'scripts' path. 'target/mik32.cfg' by default.")]
+        openocd_target: Option<PathBuf>,
+        #[arg(short, long, help="MCU type selection. Defines default openocd target. MIK32V2 by default.")]
         mcu_type: Option<MCUType>,
     },
+    /// Shut the background openocd of the selected probe down.
+    Stop {
+        #[arg(long, help="Probe profile from user config (~/.config/cargo-mik32/config.toml).")]
+        probe: Option<String>,
+        #[arg(long, help="Port of tcl openocd server the server was started with. 6666 by default.")]
+        openocd_port: Option<String>,
+        #[arg(long, help="Stop background openocd of every probe.")]
+        all: bool,
+    },
+    /// Print pid, ports and settings of every background openocd.
+    Status,
 }
 
 #[derive(ValueEnum, Clone)]
@@ -68,6 +186,25 @@ enum BootMode {
     Spifi,
 }
 
+#[derive(ValueEnum, Clone)]
+enum Backend {
+    Python,
+    Native,
+    Openocd,
+}
+
+#[derive(ValueEnum, Clone)]
+enum ObjcopyTool {
+    Native,
+    Cargo,
+}
+
+#[derive(ValueEnum, Clone)]
+enum MessageFormat {
+    Human,
+    Json,
+}
+
 #[derive(ValueEnum, Clone)]
 enum MCUType {
     MIK32V0,
@@ -75,22 +212,37 @@ enum MCUType {
 }
 
 #[allow(dead_code)]
+#[derive(Default)]
 struct FlashCmdDescriptor {
     example: Option<String>,
+    bin: Option<String>,
+    package: Option<String>,
+    profile: Option<String>,
+    features: Vec<String>,
+    no_default_features: bool,
+    target_dir: Option<PathBuf>,
     reuse: bool,
+    wait: bool,
+    dry_run: bool,
+    gdb: bool,
     gdb_exec: Option<String>,
     gdb_target_path: Option<PathBuf>,
     openocd_path: Option<PathBuf>,
     uploader_path: Option<PathBuf>,
     app_hex_path: Option<PathBuf>,
+    objcopy: Option<ObjcopyTool>,
 
     use_quad_spi: bool,
     openocd_host: Option<String>,
     openocd_port: Option<String>,
+    gdb_port: Option<String>,
     adapter_speed: Option<String>,
+    probe: Option<String>,
+    probe_serial: Option<String>,
     openocd_scripts: Option<PathBuf>,
     openocd_interface: Option<PathBuf>,
     openocd_target: Option<PathBuf>,
+    backend: Option<Backend>,
     boot_mode: Option<BootMode>,
     mcu_type: Option<MCUType>,
 
@@ -99,49 +251,177 @@ struct FlashCmdDescriptor {
 
 fn main() {
     let cli = Cli::parse();
-    let current_dir = current_dir().expect("Failed to get project directory");
+    events::init(cli.message_format.clone());
+    let verbosity = if cli.quiet { -1 } else { cli.verbose.min(2) as i8 };
+    if let Err(e) = logger::init(verbosity, cli.log_file.as_deref()) {
+        let path = cli.log_file.unwrap_or_default();
+        fail(format!("failed to create log file {}, {}", path.display(), e), exit_code::IO);
+    }
+    process::supervise();
+    let current_dir = match current_dir() {
+        Ok(dir) => dir,
+        Err(e) => fail(format!("failed to get current directory, {}", e), exit_code::IO),
+    };
     match cli.command {
-        Commands::Init { name } => {
-            init_script::make_project(name, current_dir).unwrap();
+        Commands::Doctor { fix } => {
+            if let Err(e) = doctor::doctor(current_dir, fix) {
+                fail(e.to_string(), e.exit_code());
+            }
+        }
+        Commands::Config { command: ConfigCommand::Show } => {
+            if let Err(e) = config::show(&current_dir) {
+                fail(e.to_string(), e.exit_code());
+            }
+        }
+        Commands::Server { command } => {
+            let result = match command {
+                ServerCommand::Start {
+                    openocd_path,
+                    openocd_host,
+                    openocd_port,
+                    gdb_port,
+                    adapter_speed,
+                    probe,
+                    openocd_scripts,
+                    openocd_interface,
+                    openocd_target,
+                    mcu_type,
+                } => {
+                    let mut desc = FlashCmdDescriptor {
+                        openocd_path,
+                        openocd_host,
+                        openocd_port,
+                        gdb_port,
+                        adapter_speed,
+                        probe,
+                        openocd_scripts,
+                        openocd_interface,
+                        openocd_target,
+                        mcu_type,
+                        project_dir: current_dir,
+                        ..Default::default()
+                    };
+                    server_config(&mut desc).and_then(|_| server::start(&desc))
+                }
+                ServerCommand::Stop { probe, openocd_port, all } => {
+                    let mut desc = FlashCmdDescriptor {
+                        probe,
+                        openocd_port,
+                        project_dir: current_dir,
+                        ..Default::default()
+                    };
+                    server_config(&mut desc).and_then(|_| server::stop_server(&desc, all))
+                }
+                ServerCommand::Status => server::status(),
+            };
+            if let Err(e) = result {
+                fail(e.to_string(), e.exit_code());
+            }
+        }
+        Commands::Init { name, boot_mode, mcu_type, flash_size } => {
+            if let Err(e) = init_script::make_project(name, current_dir, boot_mode, mcu_type, flash_size) {
+                fail(e.to_string(), e.exit_code());
+            }
         }
         Commands::Run { 
             example, 
+            bin,
+            package,
+            profile,
+            features,
+            no_default_features,
+            target_dir,
             reuse,
+            wait,
+            no_wait,
+            dry_run,
+            gdb,
             gdb_exec, 
             gdb_target_path,
             openocd_path, 
             uploader_path, 
             app_hex_path, 
+            objcopy,
             use_quad_spi, 
             openocd_host, 
             openocd_port, 
+            gdb_port,
             adapter_speed, 
+            probe,
             openocd_scripts, 
             openocd_interface, 
             openocd_target, 
+            backend,
             boot_mode, 
             mcu_type } => {
-                run_wrapper(FlashCmdDescriptor { 
+                let result = run_wrapper(FlashCmdDescriptor { 
                     example,
+                    bin,
+                    package,
+                    profile,
+                    features,
+                    no_default_features,
+                    target_dir,
                     reuse, 
+                    wait: wait && !no_wait,
+                    dry_run,
+                    gdb,
                     gdb_exec, 
                     gdb_target_path,
                     openocd_path,
                     uploader_path, 
                     app_hex_path, 
+                    objcopy,
                     use_quad_spi, 
                     openocd_host, 
                     openocd_port, 
+                    gdb_port,
                     adapter_speed, 
+                    probe,
+                    probe_serial: None,
                     openocd_scripts, 
                     openocd_interface, 
                     openocd_target, 
+                    backend,
                     boot_mode, 
                     mcu_type, 
                     project_dir: current_dir,
-                }).unwrap()
+                });
+                if let Err(e) = result {
+                    fail(e.to_string(), e.exit_code());
+                }
             }
     }
+    // A step may still succeed after a signal, e.g. gdb quitting on SIGTERM.
+    if let Some(code) = process::interrupted() {
+        fail("interrupted".to_owned(), code);
+    }
+}
+
+/// Project config is optional for the server, it can be started outside of a package.
+fn server_config(desc: &mut FlashCmdDescriptor) -> Result<(), build_script::RunError> {
+    if !desc.project_dir.join("Cargo.toml").exists() {
+        return Ok(());
+    }
+    config::ProjectConfig::load(&desc.project_dir)
+        .and_then(|config| config.apply(desc))
+        .map_err(build_script::RunError::BadConfig)
+}
+
+/// Reports the error and exits. After a signal the failure of the interrupted step is only logged,
+/// and the interruption is reported instead.
+fn fail(message: String, code: i32) -> ! {
+    let (message, code) = match process::interrupted() {
+        Some(signal_code) => {
+            log::debug!("{}", message);
+            ("interrupted".to_owned(), signal_code)
+        }
+        None => (message, code),
+    };
+    log::error!("{}", message);
+    events::emit(events::Event::Error { code, message: &message });
+    log::logger().flush();
+    std::process::exit(code)
 }
 
 
@@ -0,0 +1,130 @@
+use std::{fs::{self, File, OpenOptions, TryLockError}, path::Path};
+
+use log::{debug, info};
+
+use crate::{build_script::RunError, config, events, server, tcl_client::DEFAULT_TCL_HOST, FlashCmdDescriptor};
+
+/// Advisory lock on a probe, held by `run` from upload until gdb exits, so two invocations
+/// never talk to the same probe at once. The OS drops it when the holder exits, however it exits,
+/// so a crashed run never leaves the probe locked.
+///
+/// A probe is identified by its serial when one is configured, otherwise by the openocd host and TCL port.
+/// The lock file lives in the runtime directory. The holder's pid goes to a file next to it,
+/// since a locked file cannot be read on every platform.
+pub struct ProbeLock {
+    /// Empty for `--dry-run`, which takes no lock.
+    _file: Option<File>,
+}
+
+impl ProbeLock {
+    /// Takes the lock for the probe `desc` talks to. When another process holds it,
+    /// fails naming its pid, or with `--wait` blocks until it is released.
+    pub fn acquire(desc: &FlashCmdDescriptor) -> Result<ProbeLock, RunError> {
+        let probe = probe_key(desc)?;
+        let dir = config::runtime_dir().map_err(|e| RunError::io(Path::new("runtime directory"), e))?;
+        let path = dir.join(format!("probe-{}.lock", file_name(&probe)));
+        let pid_path = path.with_extension("pid");
+
+        if desc.dry_run {
+            events::dry_run("probe lock", &format!("lock {} ({})", probe, path.display()));
+            return Ok(ProbeLock { _file: None });
+        }
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| RunError::io(&path, e))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let holder = holder(&pid_path);
+                if !desc.wait {
+                    return Err(RunError::ProbeBusy { probe, holder });
+                }
+                info!("Waiting for probe {} used by {}...", probe, describe_holder(holder));
+                file.lock().map_err(|e| RunError::io(&path, e))?;
+            }
+            Err(TryLockError::Error(e)) => return Err(RunError::io(&path, e)),
+        }
+        fs::write(&pid_path, std::process::id().to_string()).map_err(|e| RunError::io(&pid_path, e))?;
+        debug!("Locked probe {} ({})", probe, path.display());
+        Ok(ProbeLock { _file: Some(file) })
+    }
+}
+
+/// Probe `desc` talks to: its serial when one is configured, otherwise the openocd host and TCL port.
+/// Every loopback name of this machine gives the same key, so `localhost` and `127.0.0.1` share a lock.
+pub fn probe_key(desc: &FlashCmdDescriptor) -> Result<String, RunError> {
+    if let Some(serial) = &desc.probe_serial {
+        return Ok(format!("serial {}", serial));
+    }
+    let host = desc.openocd_host.as_deref().unwrap_or(DEFAULT_TCL_HOST).to_ascii_lowercase();
+    let host = if server::is_local(&host) { DEFAULT_TCL_HOST.to_owned() } else { host };
+    Ok(format!("{}:{}", host, server::tcl_port(desc)?))
+}
+
+/// Probe key with everything but plain characters replaced, e.g. `127.0.0.1_6666` or `serial_FT4Z3X1`.
+pub fn file_name(probe: &str) -> String {
+    probe.chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' }).collect()
+}
+
+/// Pid of the current holder. Unknown when the holder has just taken the lock and not written it yet.
+fn holder(pid_path: &Path) -> Option<u32> {
+    fs::read_to_string(pid_path).ok()?.trim().parse().ok()
+}
+
+pub fn describe_holder(holder: Option<u32>) -> String {
+    match holder {
+        Some(pid) => format!("pid {}", pid),
+        None => "another process".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(host: Option<&str>, port: Option<&str>) -> String {
+        let desc = FlashCmdDescriptor {
+            openocd_host: host.map(str::to_owned),
+            openocd_port: port.map(str::to_owned),
+            ..Default::default()
+        };
+        probe_key(&desc).unwrap()
+    }
+
+    #[test]
+    fn loopback_names_share_a_key() {
+        for host in [None, Some("127.0.0.1"), Some("localhost"), Some("LocalHost"), Some("::1"), Some("127.0.1.1")] {
+            assert_eq!(key(host, Some("6666")), "127.0.0.1:6666", "{:?}", host);
+        }
+        assert_eq!(key(Some("localhost"), None), key(None, None));
+    }
+
+    #[test]
+    fn remote_hosts_and_ports_get_own_keys() {
+        assert_eq!(key(Some("Lab-Pi.local"), Some("6666")), "lab-pi.local:6666");
+        assert_eq!(key(Some("10.0.0.5"), Some("6666")), "10.0.0.5:6666");
+        assert_ne!(key(None, Some("6666")), key(None, Some("6667")));
+    }
+
+    #[test]
+    fn serial_wins_over_host() {
+        let desc = FlashCmdDescriptor {
+            probe_serial: Some("FT4Z3X1".to_owned()),
+            openocd_host: Some("10.0.0.5".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(probe_key(&desc).unwrap(), "serial FT4Z3X1");
+    }
+
+    #[test]
+    fn file_name_keeps_plain_characters_only() {
+        assert_eq!(file_name("127.0.0.1:6666"), "127.0.0.1_6666");
+        assert_eq!(file_name("serial FT4Z3X1"), "serial_FT4Z3X1");
+        assert_eq!(file_name("fe80::1%eth0:6666"), "fe80__1_eth0_6666");
+        assert_eq!(file_name("../../etc/passwd"), ".._.._etc_passwd");
+    }
+}
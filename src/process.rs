@@ -0,0 +1,315 @@
+use std::{collections::VecDeque, io::{self, BufRead, BufReader, Read, Write}, ops::{Deref, DerefMut}, process::{Child, Command, Output}, sync::{atomic::{AtomicI32, Ordering}, Arc, Mutex}, thread::{sleep, JoinHandle}, time::{Duration, Instant}};
+
+use log::{debug, warn};
+
+use crate::{events, logger};
+
+const TAIL_LINES: usize = 20;
+/// Time children get to exit after a forwarded signal, or when dropped while running, before they are killed.
+const GRACE: Duration = Duration::from_secs(3);
+
+/// Live children of this invocation: pid, and whether it leads a process group of its own.
+static CHILDREN: Mutex<Vec<(u32, bool)>> = Mutex::new(Vec::new());
+/// Signal that interrupted us, 0 while nothing did.
+static INTERRUPTED: AtomicI32 = AtomicI32::new(0);
+
+/// Child process that does not outlive us. It is stopped when dropped while still running,
+/// and signals we get are passed on to it, see `supervise`.
+///
+/// On unix the child leads a process group of its own. The group also holds whatever the child starts
+/// (rustc under cargo, openocd under the python uploader), so the whole tree is stopped at once,
+/// and Ctrl-C in the terminal only reaches us, which decides how to stop the children.
+pub struct Supervised {
+    child: Child,
+    group: bool,
+    detached: bool,
+}
+
+impl Supervised {
+    pub fn spawn(command: &mut Command) -> io::Result<Supervised> {
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(command, 0);
+        Ok(Supervised::register(command.spawn()?, cfg!(unix)))
+    }
+
+    /// Spawns a child that shares our terminal, i.e. gdb. It stays in the foreground process group,
+    /// so it gets Ctrl-C from the terminal itself and uses it to halt the target.
+    pub fn spawn_interactive(command: &mut Command) -> io::Result<Supervised> {
+        Ok(Supervised::register(command.spawn()?, false))
+    }
+
+    fn register(child: Child, group: bool) -> Supervised {
+        CHILDREN.lock().unwrap().push((child.id(), group));
+        Supervised { child, group, detached: false }
+    }
+
+    /// Like `Child::wait_with_output` for a child with piped stdout. Stderr is expected to be taken by `StderrTail`.
+    pub fn wait_with_output(mut self) -> io::Result<Output> {
+        let mut stdout = Vec::new();
+        if let Some(mut pipe) = self.child.stdout.take() {
+            pipe.read_to_end(&mut stdout)?;
+        }
+        let status = self.child.wait()?;
+        Ok(Output { status, stdout, stderr: Vec::new() })
+    }
+
+    /// Lets the child run on after we exit, as the background openocd does. Returns its pid.
+    pub fn detach(mut self) -> u32 {
+        self.detached = true;
+        self.child.id()
+    }
+}
+
+impl Deref for Supervised {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl DerefMut for Supervised {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl Drop for Supervised {
+    fn drop(&mut self) {
+        let pid = self.child.id();
+        CHILDREN.lock().unwrap().retain(|(child, _)| *child != pid);
+        if self.detached {
+            return;
+        }
+        if let Ok(None) = self.child.try_wait() {
+            debug!("Stopping child process {}", pid);
+            signal(pid, self.group, Signal::Terminate);
+            let deadline = Instant::now() + GRACE;
+            while matches!(self.child.try_wait(), Ok(None)) && Instant::now() < deadline {
+                sleep(Duration::from_millis(50));
+            }
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+        // Whatever the child started may still hold the probe or a port.
+        if self.group {
+            signal(pid, true, Signal::Terminate);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Signal {
+    /// The signal we got ourselves.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Forward(i32),
+    Terminate,
+    Kill,
+}
+
+#[cfg(unix)]
+fn signal(pid: u32, group: bool, signal: Signal) {
+    let signal = match signal {
+        Signal::Forward(signal) => signal,
+        Signal::Terminate => libc::SIGTERM,
+        Signal::Kill => libc::SIGKILL,
+    };
+    // A negative pid addresses the whole process group.
+    let target = if group { -(pid as libc::pid_t) } else { pid as libc::pid_t };
+    unsafe {
+        libc::kill(target, signal);
+    }
+}
+
+/// Windows has no signals to pass on. Children share our console and get Ctrl-C from it directly.
+#[cfg(not(unix))]
+fn signal(pid: u32, _group: bool, signal: Signal) {
+    if !matches!(signal, Signal::Forward(_)) {
+        let _ = Command::new("taskkill").args(["/PID", &pid.to_string(), "/T", "/F"]).output();
+    }
+}
+
+fn signal_all(signal: Signal) {
+    for (pid, group) in CHILDREN.lock().unwrap().iter() {
+        self::signal(*pid, *group, signal);
+    }
+}
+
+/// Exit code for the signal that interrupted us, 128 + signal number like a shell reports it.
+pub fn interrupted() -> Option<i32> {
+    match INTERRUPTED.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(128 + signal),
+    }
+}
+
+/// Handles SIGINT, SIGTERM and SIGHUP on a background thread. The signal is passed on to the children,
+/// the step that ran them then fails and `fail` reports the interruption. Children still running after
+/// `GRACE`, or when the signal comes again, are killed and we exit right away.
+/// Ctrl-C while gdb runs belongs to gdb and is ignored.
+#[cfg(unix)]
+pub fn supervise() {
+    use signal_hook::{consts::{SIGHUP, SIGINT, SIGTERM}, iterator::Signals};
+
+    let mut signals = match Signals::new([SIGINT, SIGTERM, SIGHUP]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("failed to install signal handlers, child processes may outlive Ctrl-C, {}", e);
+            return;
+        }
+    };
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            let interactive = CHILDREN.lock().unwrap().iter().any(|(_, group)| !group);
+            if signal == SIGINT && interactive && interrupted().is_none() {
+                continue;
+            }
+            let code = 128 + signal;
+            if INTERRUPTED.swap(signal, Ordering::SeqCst) != 0 {
+                signal_all(Signal::Kill);
+                crate::fail("interrupted again, killed child processes".to_owned(), code);
+            }
+            if CHILDREN.lock().unwrap().is_empty() {
+                crate::fail("interrupted".to_owned(), code);
+            }
+            warn!("Interrupted, stopping child processes...");
+            signal_all(Signal::Forward(signal));
+            std::thread::spawn(move || {
+                sleep(GRACE);
+                // Everything stopped in time, the main thread reports the interruption itself.
+                if CHILDREN.lock().unwrap().is_empty() {
+                    return;
+                }
+                signal_all(Signal::Kill);
+                crate::fail("child processes did not stop in time, killed them".to_owned(), code);
+            });
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn supervise() {}
+
+/// Last lines of a child's stderr, kept for error messages. Output is collected on background threads,
+/// so a chatty child never blocks on a full pipe. Every line also goes to the log file, tagged with `tool`.
+pub struct StderrTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    readers: Vec<JoinHandle<()>>,
+}
+
+impl StderrTail {
+    /// Takes stderr of `child`, which must be spawned with `Stdio::piped()`.
+    /// With `echo` every line is passed through to our own stderr as well, unless `-q` is given.
+    pub fn capture(child: &mut Child, tool: &'static str, echo: bool) -> Self {
+        let echo = echo && logger::console_shows_info();
+        let lines = Arc::new(Mutex::new(VecDeque::with_capacity(TAIL_LINES)));
+        let readers = child.stderr.take().map(|stderr| {
+            let lines = Arc::clone(&lines);
+            read_lines(stderr, move |line| {
+                if echo {
+                    eprintln!("{}", line);
+                }
+                logger::subprocess(tool, &line);
+                let mut lines = lines.lock().unwrap();
+                if lines.len() == TAIL_LINES {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            })
+        });
+        StderrTail { lines, readers: readers.into_iter().collect() }
+    }
+
+    /// Like `capture`, and also takes piped stdout of `child`, echoed to our stdout and logged.
+    /// With `--message-format json` stdout belongs to events, so the echo goes to stderr.
+    pub fn capture_all(child: &mut Child, tool: &'static str, echo: bool) -> Self {
+        let mut tail = StderrTail::capture(child, tool, echo);
+        tail.capture_stdout(child, tool, echo && logger::console_shows_info());
+        tail
+    }
+
+    /// Like `capture_all` for a console the user works in, i.e. gdb. Its stdout is shown even with `-q`.
+    pub fn capture_console(child: &mut Child, tool: &'static str) -> Self {
+        let mut tail = StderrTail::capture(child, tool, true);
+        tail.capture_stdout(child, tool, true);
+        tail
+    }
+
+    /// Output is echoed as it arrives, so a prompt without a newline shows up. Complete lines are logged.
+    fn capture_stdout(&mut self, child: &mut Child, tool: &'static str, echo: bool) {
+        let Some(mut stdout) = child.stdout.take() else {
+            return;
+        };
+        self.readers.push(std::thread::spawn(move || {
+            let mut chunk = [0u8; 4096];
+            let mut line = Vec::new();
+            loop {
+                let read = match stdout.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(read) => &chunk[..read],
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break,
+                };
+                if echo {
+                    echo_console(read);
+                }
+                for &byte in read {
+                    if byte == b'\n' {
+                        logger::subprocess(tool, String::from_utf8_lossy(&line).trim_end());
+                        line.clear();
+                    } else {
+                        line.push(byte);
+                    }
+                }
+            }
+            if !line.is_empty() {
+                logger::subprocess(tool, String::from_utf8_lossy(&line).trim_end());
+            }
+        }));
+    }
+
+    /// Waits until the child closes its output and returns the collected stderr lines.
+    pub fn finish(self) -> String {
+        for reader in self.readers {
+            let _ = reader.join();
+        }
+        let lines = self.lines.lock().unwrap();
+        lines.iter().map(String::as_str).collect::<Vec<_>>().join("\n")
+    }
+}
+
+fn echo_console(bytes: &[u8]) {
+    let _ = if events::json() {
+        let mut stderr = io::stderr().lock();
+        stderr.write_all(bytes).and_then(|_| stderr.flush())
+    } else {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(bytes).and_then(|_| stdout.flush())
+    };
+}
+
+fn read_lines(stream: impl Read + Send + 'static, mut on_line: impl FnMut(String) + Send + 'static) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in BufReader::new(stream).split(b'\n').map_while(Result::ok) {
+            on_line(String::from_utf8_lossy(&line).trim_end().to_owned());
+        }
+    })
+}
+
+/// Command line as it would be typed in a POSIX shell, for logs and `--dry-run`.
+/// Arguments with anything but plain path characters are single-quoted.
+pub fn describe(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c);
+            if !arg.is_empty() && arg.chars().all(plain) {
+                arg.into_owned()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,507 @@
+use std::{fs, net::{Ipv4Addr, Ipv6Addr, TcpListener}, path::{Path, PathBuf}, process::{Child, Stdio}, thread::sleep, time::{Duration, Instant}};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{build_script::{find_tool, RunError}, config, events, flasher::openocd_command, probe_lock, process::{self, StderrTail, Supervised}, tcl_client::{TclClient, DEFAULT_TCL_HOST, DEFAULT_TCL_PORT}, tools::{Tool, ToolError}, FlashCmdDescriptor};
+
+pub const DEFAULT_GDB_PORT: u16 = 3333;
+pub const DEFAULT_TELNET_PORT: u16 = 4444;
+/// Pidfiles of background servers are named `openocd-<probe>.json` after the probe lock, one per probe,
+/// with everything `run` needs to reuse the server. Its log goes next to it as `openocd-<probe>.log`.
+const STATE_PREFIX: &str = "openocd-";
+/// openocd needs a moment to examine the target before it answers on the TCL port.
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Ports {
+    pub tcl: u16,
+    pub gdb: u16,
+    pub telnet: u16,
+}
+
+impl Ports {
+    /// Ports for a new openocd: the defaults where they are free, otherwise ports the OS hands out,
+    /// so several boards on one host each get their own session. TCL and GDB ports given by the user are kept as is.
+    fn allocate(desc: &FlashCmdDescriptor) -> Result<Ports, RunError> {
+        let tcl = match desc.openocd_port {
+            Some(_) => tcl_port(desc)?,
+            None => free_port(DEFAULT_TCL_PORT, &[])?,
+        };
+        let gdb = match desc.gdb_port {
+            Some(_) => gdb_port(desc)?,
+            None => free_port(DEFAULT_GDB_PORT, &[tcl])?,
+        };
+        let telnet = free_port(DEFAULT_TELNET_PORT, &[tcl, gdb])?;
+        let ports = Ports { tcl, gdb, telnet };
+        debug!("openocd ports: TCL {}, GDB {}, telnet {}", ports.tcl, ports.gdb, ports.telnet);
+        Ok(ports)
+    }
+
+    /// For an openocd that only runs a script, like `program`. It then never collides with a running session.
+    pub fn disabled() -> [String; 3] {
+        ["tcl_port disabled".to_owned(), "gdb_port disabled".to_owned(), "telnet_port disabled".to_owned()]
+    }
+
+    fn commands(&self) -> [String; 3] {
+        [
+            format!("tcl_port {}", self.tcl),
+            format!("gdb_port {}", self.gdb),
+            format!("telnet_port {}", self.telnet),
+        ]
+    }
+}
+
+/// Contents of the pidfile.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ServerState {
+    pid: u32,
+    /// Probe key, see `probe_lock::probe_key`.
+    probe: String,
+    host: String,
+    ports: Ports,
+    openocd: PathBuf,
+    /// openocd arguments without port settings, to tell whether `run` asks for the same probe and target.
+    args: Vec<String>,
+    log: PathBuf,
+}
+
+/// Running openocd used by one `run` step: the one on a remote host, the background server of the probe when one is alive,
+/// otherwise a private openocd started for this step and stopped by `close`.
+pub struct Session {
+    pub host: String,
+    pub ports: Ports,
+    /// Remote or started with `server start`, left running by `close`.
+    pub external: bool,
+    owned: Option<(Supervised, StderrTail)>,
+}
+
+impl Session {
+    /// Starts or reuses openocd and waits until its TCL port answers. openocd opens the GDB and telnet ports
+    /// in the same `init` step, so they are ready as well. The GDB port itself is not probed, since a connection
+    /// there halts the target. With `echo` output of a private openocd is shown, otherwise only logged.
+    /// With `--dry-run` the openocd command line is printed and nothing is started.
+    ///
+    /// With `--openocd-host` naming another machine the probe is attached there. No local openocd is started
+    /// then, the session uses the TCL and GDB ports of the one running on that host as given.
+    pub fn open(desc: &FlashCmdDescriptor, echo: bool) -> Result<Session, RunError> {
+        let host = desc.openocd_host.clone().unwrap_or(DEFAULT_TCL_HOST.to_owned());
+        if !is_local(&host) {
+            let ports = Ports { tcl: tcl_port(desc)?, gdb: gdb_port(desc)?, telnet: DEFAULT_TELNET_PORT };
+            info!("Using openocd on {}, TCL port {}, GDB port {}", host, ports.tcl, ports.gdb);
+            if desc.dry_run {
+                events::dry_run("openocd", &format!("use remote server on {}, not started", host));
+            }
+            return Ok(Session { host, ports, external: true, owned: None });
+        }
+
+        let openocd_path = find_tool(Tool::Openocd, desc.openocd_path.as_deref(), &desc.project_dir)?;
+        if let Some(session) = Session::background(desc, &openocd_path)? {
+            return Ok(session);
+        }
+
+        let ports = Ports::allocate(desc)?;
+        let mut openocd = openocd_command(&openocd_path, desc, &ports.commands());
+        if desc.dry_run {
+            events::dry_run("openocd", &process::describe(&openocd));
+            return Ok(Session { host, ports, external: false, owned: None });
+        }
+
+        debug!("Running {}", process::describe(&openocd));
+        let mut child = Supervised::spawn(openocd.stdout(Stdio::piped()).stderr(Stdio::piped()))
+            .map_err(|e| spawn_failed(&openocd_path, e))?;
+        let stderr = StderrTail::capture_all(&mut child, "openocd", echo);
+
+        info!("Connecting to openocd at {}:{}...", host, ports.tcl);
+        if let Err(reason) = wait_ready(&host, ports.tcl, Some(&mut child)) {
+            stop(&mut child);
+            return Err(RunError::ProbeNotFound { reason, stderr: stderr.finish() });
+        }
+        Ok(Session { host, ports, external: false, owned: Some((child, stderr)) })
+    }
+
+    /// Background server started with `server start` on this machine for the probe of `desc`, when one is alive. It holds the probe,
+    /// so a server started with other settings than `desc` asks for is an error, another openocd would not get the probe.
+    /// Upload backends that start openocd on their own use it to tell whether they have to go through the server.
+    pub fn background(desc: &FlashCmdDescriptor, openocd_path: &Path) -> Result<Option<Session>, RunError> {
+        if desc.openocd_host.as_deref().is_some_and(|host| !is_local(host)) {
+            return Ok(None);
+        }
+        let Some(state) = running_server(desc)? else {
+            return Ok(None);
+        };
+        if state.openocd != openocd_path || state.args != openocd_args(openocd_path, desc) {
+            return Err(RunError::BadArgument(format!(
+                "background openocd (pid {}) runs with different settings, restart it with 'cargo mik32 server stop' and 'server start' to apply them",
+                state.pid
+            )));
+        }
+        info!("Using background openocd (pid {}) at {}:{}", state.pid, state.host, state.ports.tcl);
+        if desc.dry_run {
+            events::dry_run("openocd", &format!("reuse background server, pid {}", state.pid));
+        }
+        Ok(Some(Session { host: state.host, ports: state.ports, external: true, owned: None }))
+    }
+
+    pub fn tcl(&self) -> Result<TclClient, RunError> {
+        TclClient::connect(&self.host, self.ports.tcl, Duration::from_secs(1)).map_err(|e| RunError::ProbeNotFound {
+            reason: format!("failed to connect to openocd at {}:{}, {}", self.host, self.ports.tcl, e),
+            stderr: String::new(),
+        })
+    }
+
+    /// Shuts a private openocd down and returns the tail of its stderr. A remote or background server keeps running.
+    pub fn close(self) -> String {
+        let Some((mut child, stderr)) = self.owned else {
+            return String::new();
+        };
+        if let Ok(client) = TclClient::connect(&self.host, self.ports.tcl, Duration::from_secs(1)) {
+            client.shutdown();
+        }
+        stop(&mut child);
+        stderr.finish()
+    }
+}
+
+pub fn tcl_port(desc: &FlashCmdDescriptor) -> Result<u16, RunError> {
+    parse_port(desc.openocd_port.as_deref(), "openocd", DEFAULT_TCL_PORT)
+}
+
+fn gdb_port(desc: &FlashCmdDescriptor) -> Result<u16, RunError> {
+    parse_port(desc.gdb_port.as_deref(), "gdb", DEFAULT_GDB_PORT)
+}
+
+fn parse_port(port: Option<&str>, what: &str, default: u16) -> Result<u16, RunError> {
+    match port {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| RunError::BadArgument(format!("invalid {} port '{}'", what, port))),
+        None => Ok(default),
+    }
+}
+
+/// `preferred` when nothing listens on it, otherwise a free port picked by the OS. Ports in `taken` are
+/// already given to this openocd. The port is only reserved once openocd binds it, a race with another
+/// program grabbing it in between shows up as openocd failing to start.
+fn free_port(preferred: u16, taken: &[u16]) -> Result<u16, RunError> {
+    if !taken.contains(&preferred) && TcpListener::bind((Ipv4Addr::LOCALHOST, preferred)).is_ok() {
+        return Ok(preferred);
+    }
+    loop {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|listener| listener.local_addr())
+            .map_err(|e| RunError::io(Path::new("local TCP port"), e))?
+            .port();
+        if !taken.contains(&port) {
+            return Ok(port);
+        }
+    }
+}
+
+fn openocd_args(openocd_path: &Path, desc: &FlashCmdDescriptor) -> Vec<String> {
+    openocd_command(openocd_path, desc, &[])
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+fn spawn_failed(openocd_path: &Path, error: std::io::Error) -> RunError {
+    RunError::Tool(ToolError::Invalid { tool: Tool::Openocd, path: openocd_path.to_path_buf(), reason: error.to_string() })
+}
+
+/// Polls the TCL port until openocd answers `version`. Gives up early when `child` exits.
+fn wait_ready(host: &str, port: u16, mut child: Option<&mut Child>) -> Result<(), String> {
+    let deadline = Instant::now() + READY_TIMEOUT;
+    loop {
+        if let Some(child) = child.as_mut()
+            && let Ok(Some(status)) = child.try_wait()
+        {
+            return Err(format!("openocd exited with {} before it was ready", status));
+        }
+        let attempt = TclClient::connect(host, port, Duration::from_millis(200))
+            .and_then(|mut client| client.execute("version"));
+        match attempt {
+            Ok(_) => return Ok(()),
+            Err(e) if Instant::now() >= deadline => {
+                return Err(format!("openocd did not answer at {}:{} in time, {}", host, port, e));
+            }
+            Err(_) => sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+/// Gives openocd a moment to exit after `shutdown` and kills it if it is still alive.
+fn stop(child: &mut Child) {
+    for _ in 0..20 {
+        if let Ok(Some(_)) = child.try_wait() {
+            return;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+fn runtime_dir() -> Result<PathBuf, RunError> {
+    config::runtime_dir().map_err(|e| RunError::io(Path::new("runtime directory"), e))
+}
+
+/// Pidfile of the background server for the probe `desc` talks to.
+fn state_path(desc: &FlashCmdDescriptor) -> Result<PathBuf, RunError> {
+    let probe = probe_lock::probe_key(desc)?;
+    Ok(runtime_dir()?.join(format!("{}{}.json", STATE_PREFIX, probe_lock::file_name(&probe))))
+}
+
+/// Background server of the probe `desc` talks to, if its process is still alive.
+fn running_server(desc: &FlashCmdDescriptor) -> Result<Option<ServerState>, RunError> {
+    Ok(load_state(&state_path(desc)?))
+}
+
+/// Every background server that is still alive, with its pidfile.
+fn running_servers() -> Result<Vec<(PathBuf, ServerState)>, RunError> {
+    let dir = runtime_dir()?;
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(RunError::io(&dir, e)),
+    };
+    let mut servers: Vec<(PathBuf, ServerState)> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension().is_some_and(|ext| ext == "json")
+                && path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(STATE_PREFIX))
+        })
+        .filter_map(|path| load_state(&path).map(|state| (path, state)))
+        .collect();
+    servers.sort_by(|a, b| a.1.probe.cmp(&b.1.probe));
+    Ok(servers)
+}
+
+/// Server from a pidfile, if its process is still alive. A stale pidfile is removed.
+fn load_state(path: &Path) -> Option<ServerState> {
+    let state: ServerState = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    if process_alive(state.pid) {
+        Some(state)
+    } else {
+        debug!("Removing stale pidfile {}", path.display());
+        let _ = fs::remove_file(path);
+        None
+    }
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // Signal 0 only checks that the process exists and may be signalled.
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+#[cfg(not(unix))]
+fn process_alive(pid: u32) -> bool {
+    std::process::Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/NH"])
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains(&pid.to_string()))
+}
+
+#[cfg(unix)]
+fn terminate(pid: u32) {
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+}
+
+#[cfg(not(unix))]
+fn terminate(pid: u32) {
+    let _ = std::process::Command::new("taskkill").args(["/PID", &pid.to_string(), "/F"]).status();
+}
+
+/// Whether `host` is this machine, so openocd for it may be started here.
+pub fn is_local(host: &str) -> bool {
+    host == "localhost" || host.parse::<Ipv4Addr>().is_ok_and(|ip| ip.is_loopback()) || host.parse::<Ipv6Addr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// `cargo mik32 server start`: starts openocd in the background, detached from the terminal,
+/// with output going to a log file in the runtime directory. `run` picks it up instead of starting its own.
+/// Each probe gets its own server.
+pub fn start(desc: &FlashCmdDescriptor) -> Result<(), RunError> {
+    if let Some(state) = running_server(desc)? {
+        info!("openocd for {} is already running (pid {}), TCL port {}", state.probe, state.pid, state.ports.tcl);
+        return Ok(());
+    }
+    let host = desc.openocd_host.clone().unwrap_or(DEFAULT_TCL_HOST.to_owned());
+    if !is_local(&host) {
+        return Err(RunError::BadArgument(format!("background openocd can only run on this machine, not on {}", host)));
+    }
+
+    let openocd_path = find_tool(Tool::Openocd, desc.openocd_path.as_deref(), &desc.project_dir)?;
+    let ports = Ports::allocate(desc)?;
+    let state_path = state_path(desc)?;
+    let log_path = state_path.with_extension("log");
+    let log = fs::File::create(&log_path).map_err(|e| RunError::io(&log_path, e))?;
+    let log_err = log.try_clone().map_err(|e| RunError::io(&log_path, e))?;
+
+    let mut openocd = openocd_command(&openocd_path, desc, &ports.commands());
+    openocd.stdin(Stdio::null()).stdout(log).stderr(log_err);
+    debug!("Running {}", process::describe(&openocd));
+    // Supervised until it is ready, so Ctrl-C during startup does not leave a server without a pidfile.
+    // Its own process group keeps Ctrl-C in this terminal away from it later on.
+    let mut child = Supervised::spawn(&mut openocd).map_err(|e| spawn_failed(&openocd_path, e))?;
+
+    info!("Starting openocd...");
+    if let Err(reason) = wait_ready(&host, ports.tcl, Some(&mut child)) {
+        stop(&mut child);
+        return Err(RunError::ProbeNotFound { reason, stderr: log_tail(&log_path) });
+    }
+
+    let state = ServerState {
+        pid: child.detach(),
+        probe: probe_lock::probe_key(desc)?,
+        host,
+        ports,
+        args: openocd_args(&openocd_path, desc),
+        openocd: openocd_path,
+        log: log_path,
+    };
+    let json = serde_json::to_string_pretty(&state).expect("server state serializes");
+    fs::write(&state_path, json).map_err(|e| RunError::io(&state_path, e))?;
+    info!(
+        "Started openocd for {} (pid {}), TCL port {}, GDB port {}, telnet port {}, log {}",
+        state.probe,
+        state.pid,
+        state.ports.tcl,
+        state.ports.gdb,
+        state.ports.telnet,
+        state.log.display()
+    );
+    Ok(())
+}
+
+/// `cargo mik32 server stop`: stops the background server of the probe `desc` talks to,
+/// or with `all` every background server.
+pub fn stop_server(desc: &FlashCmdDescriptor, all: bool) -> Result<(), RunError> {
+    let servers = match all {
+        true => running_servers()?,
+        false => {
+            let path = state_path(desc)?;
+            load_state(&path).map(|state| (path, state)).into_iter().collect()
+        }
+    };
+    if servers.is_empty() {
+        info!("openocd is not running");
+    }
+    for (path, state) in servers {
+        shut_down(&path, &state)?;
+    }
+    Ok(())
+}
+
+/// Asks a background server to shut down, kills it if it does not, and removes its pidfile.
+fn shut_down(path: &Path, state: &ServerState) -> Result<(), RunError> {
+    if let Ok(client) = TclClient::connect(&state.host, state.ports.tcl, Duration::from_secs(1)) {
+        client.shutdown();
+    }
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while process_alive(state.pid) && Instant::now() < deadline {
+        sleep(Duration::from_millis(100));
+    }
+    if process_alive(state.pid) {
+        warn!("openocd (pid {}) did not shut down, terminating it", state.pid);
+        terminate(state.pid);
+    }
+    fs::remove_file(path).map_err(|e| RunError::io(path, e))?;
+    info!("Stopped openocd for {} (pid {})", state.probe, state.pid);
+    Ok(())
+}
+
+/// `cargo mik32 server status`: prints pid, ports, probe settings and whether openocd answers, for every background server.
+pub fn status() -> Result<(), RunError> {
+    let servers = running_servers()?;
+    if servers.is_empty() {
+        println!("openocd is not running");
+    }
+    for (i, (_, state)) in servers.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print_state(state);
+    }
+    Ok(())
+}
+
+fn print_state(state: &ServerState) {
+    let answers = TclClient::connect(&state.host, state.ports.tcl, Duration::from_secs(1))
+        .and_then(|mut client| client.execute("version"));
+    println!("openocd for {} is running, pid {}", state.probe, state.pid);
+    println!("  executable  {}", state.openocd.display());
+    println!("  arguments   {}", state.args.join(" "));
+    println!("  host        {}", state.host);
+    println!("  tcl port    {}", state.ports.tcl);
+    println!("  gdb port    {}", state.ports.gdb);
+    println!("  telnet port {}", state.ports.telnet);
+    println!("  log         {}", state.log.display());
+    match answers {
+        Ok(version) => println!("  responding  yes, {}", version.trim()),
+        Err(e) => println!("  responding  no, {}", e),
+    }
+}
+
+fn log_tail(path: &Path) -> String {
+    let text = fs::read_to_string(path).unwrap_or_default();
+    let lines: Vec<&str> = text.lines().collect();
+    lines[lines.len().saturating_sub(20)..].join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Port nothing listens on right now.
+    fn unused_port() -> u16 {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn free_port_keeps_preferred_port_when_nothing_listens() {
+        let port = unused_port();
+        assert_eq!(free_port(port, &[]).unwrap(), port);
+    }
+
+    #[test]
+    fn free_port_skips_busy_and_taken_ports() {
+        let busy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let busy = busy.local_addr().unwrap().port();
+        assert_ne!(free_port(busy, &[]).unwrap(), busy);
+
+        let taken = unused_port();
+        let port = free_port(taken, &[taken]).unwrap();
+        assert_ne!(port, taken);
+    }
+
+    #[test]
+    fn allocate_keeps_given_ports_even_when_busy() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let tcl = listener.local_addr().unwrap().port();
+        let gdb = unused_port();
+        let desc = FlashCmdDescriptor {
+            openocd_port: Some(tcl.to_string()),
+            gdb_port: Some(gdb.to_string()),
+            ..Default::default()
+        };
+        let ports = Ports::allocate(&desc).unwrap();
+        assert_eq!((ports.tcl, ports.gdb), (tcl, gdb));
+        assert!(ports.telnet != tcl && ports.telnet != gdb);
+    }
+
+    #[test]
+    fn allocate_gives_every_server_its_own_port() {
+        let ports = Ports::allocate(&FlashCmdDescriptor::default()).unwrap();
+        assert!(ports.tcl != ports.gdb && ports.gdb != ports.telnet && ports.tcl != ports.telnet);
+    }
+
+    #[test]
+    fn allocate_rejects_invalid_port() {
+        let desc = FlashCmdDescriptor { gdb_port: Some("33333333".to_owned()), ..Default::default() };
+        assert!(matches!(Ports::allocate(&desc), Err(RunError::BadArgument(_))));
+    }
+}
@@ -0,0 +1,451 @@
+use std::{thread::sleep, time::{Duration, Instant}};
+
+use crate::{chip::MemoryRegion, ihex::{split_into_pages, Segment}, tcl_client::{TclClient, TclError}};
+
+const PM_CLK_AHB_SET: u32 = 0x0005_000C;
+const PM_CLOCK_AHB_SPIFI: u32 = 1 << 3;
+
+const SPIFI_CONFIG: u32 = 0x0007_0000;
+const SPIFI_CONFIG_CMD: u32 = SPIFI_CONFIG + 0x04;
+const SPIFI_CONFIG_ADDR: u32 = SPIFI_CONFIG + 0x08;
+const SPIFI_CONFIG_IDATA: u32 = SPIFI_CONFIG + 0x0C;
+const SPIFI_CONFIG_CLIMIT: u32 = SPIFI_CONFIG + 0x10;
+const SPIFI_CONFIG_DATA: u32 = SPIFI_CONFIG + 0x14;
+const SPIFI_CONFIG_STAT: u32 = SPIFI_CONFIG + 0x1C;
+
+const STAT_CMD: u32 = 1 << 1;
+const STAT_RESET: u32 = 1 << 4;
+const STAT_INTRQ: u32 = 1 << 5;
+
+const CMD_DOUT_S: u32 = 15;
+const CMD_FIELDFORM_S: u32 = 19;
+const CMD_FRAMEFORM_S: u32 = 21;
+const CMD_OPCODE_S: u32 = 24;
+
+const FIELDFORM_ALL_SERIAL: u32 = 0;
+const FIELDFORM_DATA_PARALLEL: u32 = 1;
+const FRAMEFORM_OPCODE_NOADDR: u32 = 1;
+const FRAMEFORM_OPCODE_3ADDR: u32 = 4;
+
+const OP_WRITE_ENABLE: u8 = 0x06;
+const OP_READ_SREG1: u8 = 0x05;
+const OP_READ_SREG2: u8 = 0x35;
+const OP_WRITE_SREG: u8 = 0x01;
+const OP_SECTOR_ERASE: u8 = 0x20;
+const OP_PAGE_PROGRAM: u8 = 0x02;
+const OP_QUAD_PAGE_PROGRAM: u8 = 0x32;
+const OP_READ_DATA: u8 = 0x03;
+const OP_JEDEC_ID: u8 = 0x9F;
+
+const SREG1_BUSY: u8 = 1 << 0;
+const SREG1_QE: u8 = 1 << 6;
+const SREG2_QE: u8 = 1 << 1;
+
+const MANUFACTURER_MACRONIX: u8 = 0xC2;
+const MANUFACTURER_ISSI: u8 = 0x9D;
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+const ERASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum SpifiError {
+    Tcl(TclError),
+    NoChip,
+    OutOfRange(u32),
+    Timeout,
+    QuadEnableFailed,
+    VerifyFailed(u32),
+}
+
+impl std::fmt::Display for SpifiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpifiError::Tcl(e) => write!(f, "{}", e),
+            SpifiError::NoChip => write!(f, "external flash did not answer JEDEC ID request"),
+            SpifiError::OutOfRange(address) => write!(f, "address 0x{:08x} is outside of external flash", address),
+            SpifiError::Timeout => write!(f, "external flash stayed busy for too long"),
+            SpifiError::QuadEnableFailed => write!(f, "failed to set QE bit of external flash"),
+            SpifiError::VerifyFailed(address) => write!(f, "verification failed at page 0x{:08x}", address),
+        }
+    }
+}
+
+impl From<TclError> for SpifiError {
+    fn from(err: TclError) -> Self {
+        SpifiError::Tcl(err)
+    }
+}
+
+/// External flash identified by its JEDEC ID.
+pub struct FlashChip {
+    /// Manufacturer, memory type and capacity bytes as read.
+    pub jedec_id: [u8; 3],
+    pub manufacturer: u8,
+    pub capacity: u32,
+}
+
+impl FlashChip {
+    pub fn vendor(&self) -> &'static str {
+        match self.manufacturer {
+            0xEF => "Winbond",
+            0xC8 => "GigaDevice",
+            MANUFACTURER_MACRONIX => "Macronix",
+            MANUFACTURER_ISSI => "ISSI",
+            0x20 => "Micron",
+            0x1F => "Adesto",
+            _ => "Unknown",
+        }
+    }
+
+    /// Macronix and ISSI keep QE in status register 1, most others in status register 2.
+    fn qe_in_sreg1(&self) -> bool {
+        matches!(self.manufacturer, MANUFACTURER_MACRONIX | MANUFACTURER_ISSI)
+    }
+}
+
+/// Programs image segments into external flash through the SPIFI command interface.
+/// Touched sectors are erased, then written page by page and read back.
+/// With `use_quad_spi` the QE bit is set and pages are written with Quad Page Program.
+/// `region` is the SPIFI window of the selected chip. Target has to be halted beforehand.
+pub fn program(
+    client: &mut TclClient,
+    region: &MemoryRegion,
+    segments: &[Segment],
+    use_quad_spi: bool,
+    mut on_page: impl FnMut(usize, usize),
+) -> Result<FlashChip, SpifiError> {
+    init(client)?;
+    let chip = read_jedec_id(client)?;
+    let pages = split_into_pages(segments, region.start, region.size.min(chip.capacity), region.page_size, 0xFF)
+        .map_err(SpifiError::OutOfRange)?;
+
+    if use_quad_spi {
+        quad_enable(client, &chip)?;
+    }
+
+    let mut sectors: Vec<u32> = pages.keys().map(|offset| offset - offset % region.erase_size).collect();
+    sectors.dedup();
+    for sector in sectors {
+        erase_sector(client, sector)?;
+    }
+
+    for (done, (offset, page)) in pages.iter().enumerate() {
+        write_page(client, *offset, page, use_quad_spi)?;
+        verify_page(client, region, *offset, page)?;
+        on_page(done + 1, pages.len());
+    }
+    Ok(chip)
+}
+
+fn init(client: &mut TclClient) -> Result<(), SpifiError> {
+    client.write_word(PM_CLK_AHB_SET, PM_CLOCK_AHB_SPIFI)?;
+    let stat = client.read_word(SPIFI_CONFIG_STAT)?;
+    client.write_word(SPIFI_CONFIG_STAT, stat | STAT_INTRQ | STAT_RESET)?;
+    wait_stat_clear(client, STAT_RESET)?;
+    client.write_word(SPIFI_CONFIG_ADDR, 0)?;
+    client.write_word(SPIFI_CONFIG_IDATA, 0)?;
+    client.write_word(SPIFI_CONFIG_CLIMIT, 0)?;
+    Ok(())
+}
+
+fn read_jedec_id(client: &mut TclClient) -> Result<FlashChip, SpifiError> {
+    let id = command_read(client, OP_JEDEC_ID, None, 3)?;
+    if id.iter().all(|b| *b == 0x00) || id.iter().all(|b| *b == 0xFF) || !(10..=31).contains(&id[2]) {
+        return Err(SpifiError::NoChip);
+    }
+    Ok(FlashChip {
+        jedec_id: [id[0], id[1], id[2]],
+        manufacturer: id[0],
+        capacity: 1 << id[2],
+    })
+}
+
+fn quad_enable(client: &mut TclClient, chip: &FlashChip) -> Result<(), SpifiError> {
+    let sreg1 = command_read(client, OP_READ_SREG1, None, 1)?[0];
+    if chip.qe_in_sreg1() {
+        if sreg1 & SREG1_QE == 0 {
+            write_enable(client)?;
+            command_write(client, OP_WRITE_SREG, None, &[sreg1 | SREG1_QE], FIELDFORM_ALL_SERIAL)?;
+            wait_busy(client, COMMAND_TIMEOUT)?;
+        }
+        if command_read(client, OP_READ_SREG1, None, 1)?[0] & SREG1_QE == 0 {
+            return Err(SpifiError::QuadEnableFailed);
+        }
+    } else {
+        let sreg2 = command_read(client, OP_READ_SREG2, None, 1)?[0];
+        if sreg2 & SREG2_QE == 0 {
+            write_enable(client)?;
+            command_write(client, OP_WRITE_SREG, None, &[sreg1, sreg2 | SREG2_QE], FIELDFORM_ALL_SERIAL)?;
+            wait_busy(client, COMMAND_TIMEOUT)?;
+        }
+        if command_read(client, OP_READ_SREG2, None, 1)?[0] & SREG2_QE == 0 {
+            return Err(SpifiError::QuadEnableFailed);
+        }
+    }
+    Ok(())
+}
+
+fn erase_sector(client: &mut TclClient, offset: u32) -> Result<(), SpifiError> {
+    write_enable(client)?;
+    command_write(client, OP_SECTOR_ERASE, Some(offset), &[], FIELDFORM_ALL_SERIAL)?;
+    wait_busy(client, ERASE_TIMEOUT)
+}
+
+fn write_page(client: &mut TclClient, offset: u32, page: &[u8], use_quad_spi: bool) -> Result<(), SpifiError> {
+    write_enable(client)?;
+    if use_quad_spi {
+        command_write(client, OP_QUAD_PAGE_PROGRAM, Some(offset), page, FIELDFORM_DATA_PARALLEL)?;
+    } else {
+        command_write(client, OP_PAGE_PROGRAM, Some(offset), page, FIELDFORM_ALL_SERIAL)?;
+    }
+    wait_busy(client, COMMAND_TIMEOUT)
+}
+
+fn verify_page(client: &mut TclClient, region: &MemoryRegion, offset: u32, page: &[u8]) -> Result<(), SpifiError> {
+    let data = command_read(client, OP_READ_DATA, Some(offset), page.len() as u32)?;
+    if data != page {
+        return Err(SpifiError::VerifyFailed(region.start + offset));
+    }
+    Ok(())
+}
+
+fn write_enable(client: &mut TclClient) -> Result<(), SpifiError> {
+    command_write(client, OP_WRITE_ENABLE, None, &[], FIELDFORM_ALL_SERIAL)
+}
+
+/// Polls status register 1 until the chip drops its BUSY flag.
+fn wait_busy(client: &mut TclClient, timeout: Duration) -> Result<(), SpifiError> {
+    let deadline = Instant::now() + timeout;
+    while command_read(client, OP_READ_SREG1, None, 1)?[0] & SREG1_BUSY != 0 {
+        if Instant::now() >= deadline {
+            return Err(SpifiError::Timeout);
+        }
+        sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+fn wait_stat_clear(client: &mut TclClient, mask: u32) -> Result<(), SpifiError> {
+    let deadline = Instant::now() + COMMAND_TIMEOUT;
+    while client.read_word(SPIFI_CONFIG_STAT)? & mask != 0 {
+        if Instant::now() >= deadline {
+            return Err(SpifiError::Timeout);
+        }
+        sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+fn start_command(
+    client: &mut TclClient,
+    opcode: u8,
+    address: Option<u32>,
+    data_len: u32,
+    dout: bool,
+    fieldform: u32,
+) -> Result<(), SpifiError> {
+    let frameform = match address {
+        Some(address) => {
+            client.write_word(SPIFI_CONFIG_ADDR, address)?;
+            FRAMEFORM_OPCODE_3ADDR
+        }
+        None => FRAMEFORM_OPCODE_NOADDR,
+    };
+    client.write_word(SPIFI_CONFIG_STAT, STAT_INTRQ)?;
+    client.write_word(
+        SPIFI_CONFIG_CMD,
+        ((opcode as u32) << CMD_OPCODE_S)
+            | (frameform << CMD_FRAMEFORM_S)
+            | (fieldform << CMD_FIELDFORM_S)
+            | ((dout as u32) << CMD_DOUT_S)
+            | data_len,
+    )?;
+    Ok(())
+}
+
+fn command_write(
+    client: &mut TclClient,
+    opcode: u8,
+    address: Option<u32>,
+    data: &[u8],
+    fieldform: u32,
+) -> Result<(), SpifiError> {
+    start_command(client, opcode, address, data.len() as u32, true, fieldform)?;
+    let values: Vec<u32> = data.iter().map(|b| *b as u32).collect();
+    client.write_repeated(SPIFI_CONFIG_DATA, 8, &values)?;
+    wait_stat_clear(client, STAT_CMD)
+}
+
+fn command_read(client: &mut TclClient, opcode: u8, address: Option<u32>, len: u32) -> Result<Vec<u8>, SpifiError> {
+    start_command(client, opcode, address, len, false, FIELDFORM_ALL_SERIAL)?;
+    let values = client.read_repeated(SPIFI_CONFIG_DATA, 8, len)?;
+    wait_stat_clear(client, STAT_CMD)?;
+    Ok(values.into_iter().map(|v| v as u8).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, VecDeque};
+
+    use super::*;
+    use crate::{chip::chip, tcl_client::fake::{self, Target}};
+
+    /// SPIFI controller with a flash chip behind it. Commands complete at once, so STAT never reports busy.
+    /// Read commands queue their answer for DATA reads, write commands run once all data bytes arrived.
+    struct Simulated {
+        jedec_id: [u8; 3],
+        sreg1: u8,
+        sreg2: u8,
+        write_enabled: bool,
+        memory: HashMap<u32, u8>,
+        address: u32,
+        opcode: u8,
+        data_len: usize,
+        data_in: Vec<u8>,
+        data_out: VecDeque<u8>,
+        status_writes: Vec<Vec<u8>>,
+        erased: Vec<u32>,
+    }
+
+    impl Simulated {
+        fn new(jedec_id: [u8; 3]) -> Self {
+            Simulated {
+                jedec_id,
+                sreg1: 0,
+                sreg2: 0,
+                write_enabled: false,
+                memory: HashMap::new(),
+                address: 0,
+                opcode: 0,
+                data_len: 0,
+                data_in: Vec::new(),
+                data_out: VecDeque::new(),
+                status_writes: Vec::new(),
+                erased: Vec::new(),
+            }
+        }
+
+        fn run_write_command(&mut self) {
+            let data = std::mem::take(&mut self.data_in);
+            match self.opcode {
+                OP_WRITE_ENABLE => self.write_enabled = true,
+                OP_WRITE_SREG if self.write_enabled => {
+                    self.sreg1 = data[0];
+                    if let Some(sreg2) = data.get(1) {
+                        self.sreg2 = *sreg2;
+                    }
+                    self.status_writes.push(data);
+                }
+                OP_SECTOR_ERASE if self.write_enabled => {
+                    let sector = self.address & !0xFFF;
+                    self.memory.retain(|address, _| address & !0xFFF != sector);
+                    self.erased.push(sector);
+                }
+                OP_PAGE_PROGRAM | OP_QUAD_PAGE_PROGRAM if self.write_enabled => {
+                    for (i, byte) in data.iter().enumerate() {
+                        self.memory.insert(self.address + i as u32, *byte);
+                    }
+                }
+                _ => {}
+            }
+            if self.opcode != OP_WRITE_ENABLE {
+                self.write_enabled = false;
+            }
+        }
+    }
+
+    impl Target for Simulated {
+        fn read(&mut self, address: u32, _width: u32) -> u32 {
+            match address {
+                SPIFI_CONFIG_DATA => self.data_out.pop_front().unwrap_or(0) as u32,
+                _ => 0,
+            }
+        }
+
+        fn write(&mut self, address: u32, _width: u32, value: u32) {
+            match address {
+                SPIFI_CONFIG_ADDR => self.address = value,
+                SPIFI_CONFIG_CMD => {
+                    self.opcode = (value >> CMD_OPCODE_S) as u8;
+                    self.data_len = (value & 0x3FFF) as usize;
+                    self.data_in.clear();
+                    if value & (1 << CMD_DOUT_S) == 0 {
+                        self.data_out = match self.opcode {
+                            OP_JEDEC_ID => self.jedec_id.to_vec(),
+                            OP_READ_SREG1 => vec![self.sreg1],
+                            OP_READ_SREG2 => vec![self.sreg2],
+                            OP_READ_DATA => (0..self.data_len as u32)
+                                .map(|i| *self.memory.get(&(self.address + i)).unwrap_or(&0xFF))
+                                .collect(),
+                            _ => Vec::new(),
+                        }
+                        .into();
+                    } else if self.data_len == 0 {
+                        self.run_write_command();
+                    }
+                }
+                SPIFI_CONFIG_DATA => {
+                    self.data_in.push(value as u8);
+                    if self.data_in.len() == self.data_len {
+                        self.run_write_command();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn program_simulated(target: Simulated, segments: &[Segment], use_quad_spi: bool) -> (Result<FlashChip, SpifiError>, Simulated) {
+        fake::session(target, |client| program(client, &chip(None).spifi, segments, use_quad_spi, |_, _| {}))
+    }
+
+    fn segment(offset: u32, len: usize) -> Segment {
+        Segment { address: 0x8000_0000 + offset, data: (0..len).map(|i| i as u8).collect() }
+    }
+
+    #[test]
+    fn blank_jedec_id_means_no_chip() {
+        for id in [[0x00; 3], [0xFF; 3]] {
+            let (result, _) = program_simulated(Simulated::new(id), &[segment(0, 16)], false);
+            assert!(matches!(result, Err(SpifiError::NoChip)));
+        }
+    }
+
+    #[test]
+    fn quad_enable_in_sreg1_for_macronix_and_issi() {
+        for manufacturer in [MANUFACTURER_MACRONIX, MANUFACTURER_ISSI] {
+            let (result, target) = program_simulated(Simulated::new([manufacturer, 0x20, 0x16]), &[segment(0, 16)], true);
+            result.unwrap();
+            assert_eq!(target.status_writes, vec![vec![SREG1_QE]]);
+            assert_eq!(target.sreg2, 0);
+        }
+    }
+
+    #[test]
+    fn quad_enable_in_sreg2_for_other_vendors() {
+        let (result, target) = program_simulated(Simulated::new([0xEF, 0x40, 0x18]), &[segment(0, 16)], true);
+        let chip = result.unwrap();
+        assert_eq!(chip.jedec_id, [0xEF, 0x40, 0x18]);
+        assert_eq!(chip.capacity, 16 * 1024 * 1024);
+        assert_eq!(target.status_writes, vec![vec![0, SREG2_QE]]);
+        assert_eq!(target.sreg1 & SREG1_QE, 0);
+    }
+
+    #[test]
+    fn every_touched_sector_is_erased_once() {
+        // Two pages of sector 0, then a segment running from the end of sector 0 into sector 1.
+        let segments = [segment(0, 300), segment(0xF00, 0x200)];
+        let (result, target) = program_simulated(Simulated::new([0xEF, 0x40, 0x18]), &segments, false);
+        result.unwrap();
+        assert_eq!(target.erased, vec![0, 0x1000]);
+        assert_eq!(target.memory.get(&0x1000), Some(&0x00));
+    }
+
+    #[test]
+    fn address_beyond_capacity_is_rejected() {
+        // 2^20 bytes, 1 MiB.
+        let (result, target) = program_simulated(Simulated::new([0xEF, 0x40, 0x14]), &[segment(0xF_FFF0, 32)], false);
+        assert!(matches!(result, Err(SpifiError::OutOfRange(0x8010_0000))));
+        assert!(target.erased.is_empty());
+    }
+}
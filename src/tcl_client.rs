@@ -0,0 +1,398 @@
+use std::{io::{Read, Write}, net::{TcpStream, ToSocketAddrs}, time::Duration};
+
+/// Every message on the OpenOCD TCL-RPC link, in both directions, is terminated by this byte.
+pub const TCL_TERMINATOR: u8 = 0x1a;
+pub const DEFAULT_TCL_HOST: &str = "127.0.0.1";
+pub const DEFAULT_TCL_PORT: u16 = 6666;
+
+#[derive(Debug)]
+pub enum TclError {
+    ConnectFailed,
+    Io(std::io::Error),
+    BadReply,
+    CommandFailed(String),
+}
+
+impl std::fmt::Display for TclError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TclError::ConnectFailed => write!(f, "could not connect to openocd TCL server"),
+            TclError::Io(e) => write!(f, "TCL connection error: {}", e),
+            TclError::BadReply => write!(f, "malformed reply from openocd TCL server"),
+            TclError::CommandFailed(msg) => write!(f, "openocd command failed: {}", msg),
+        }
+    }
+}
+
+impl From<std::io::Error> for TclError {
+    fn from(err: std::io::Error) -> Self {
+        TclError::Io(err)
+    }
+}
+
+/// Client for the OpenOCD TCL-RPC server (port 6666 by default).
+/// Commands are wrapped in `catch` so a failing command is reported as `TclError::CommandFailed`
+/// instead of being mixed up with its regular result.
+pub struct TclClient {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl TclClient {
+    pub fn connect(host: &str, port: u16, timeout: Duration) -> Result<Self, TclError> {
+        let addrs = (host, port).to_socket_addrs()?;
+        for addr in addrs {
+            if let Ok(stream) = TcpStream::connect_timeout(&addr, timeout) {
+                stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+                stream.set_nodelay(true)?;
+                return Ok(TclClient { stream, buffer: Vec::new() });
+            }
+        }
+        Err(TclError::ConnectFailed)
+    }
+
+    /// Sends a raw TCL script and returns the reply without the terminator.
+    pub fn send_raw(&mut self, script: &str) -> Result<String, TclError> {
+        let mut message = Vec::with_capacity(script.len() + 1);
+        message.extend_from_slice(script.as_bytes());
+        message.push(TCL_TERMINATOR);
+        log::trace!("tcl > {}", script);
+        self.stream.write_all(&message)?;
+        self.stream.flush()?;
+        let reply = self.read_reply()?;
+        log::trace!("tcl < {}", reply);
+        Ok(reply)
+    }
+
+    /// Runs an OpenOCD command (or a short TCL script) and returns its result.
+    pub fn execute(&mut self, command: &str) -> Result<String, TclError> {
+        let script = format!(
+            "set _mik32_rc [catch {{{command}}} _mik32_msg]; format \"%d %s\" $_mik32_rc $_mik32_msg"
+        );
+        let reply = self.send_raw(&script)?;
+        parse_reply(&reply)
+    }
+
+    pub fn reset_halt(&mut self) -> Result<(), TclError> {
+        self.execute("reset halt").map(|_| ())
+    }
+
+    pub fn reset_run(&mut self) -> Result<(), TclError> {
+        self.execute("reset run").map(|_| ())
+    }
+
+    pub fn resume(&mut self) -> Result<(), TclError> {
+        self.execute("resume").map(|_| ())
+    }
+
+    /// Sets a core register of the halted target, e.g. `pc` or `sp`.
+    pub fn write_register(&mut self, name: &str, value: u32) -> Result<(), TclError> {
+        self.execute(&format!("reg {name} 0x{value:08x}")).map(|_| ())
+    }
+
+    /// Asks the server to terminate. openocd closes the connection right away, so the reply is not awaited.
+    pub fn shutdown(mut self) {
+        let _ = self.stream.write_all(b"shutdown\x1a");
+    }
+
+    pub fn write_word(&mut self, address: u32, value: u32) -> Result<(), TclError> {
+        self.execute(&format!("mww 0x{address:08x} 0x{value:08x}")).map(|_| ())
+    }
+
+    /// Writes consecutive values of `width` bits (8, 16 or 32) starting from `address` in one round trip.
+    pub fn write_memory(&mut self, address: u32, width: u32, values: &[u32]) -> Result<(), TclError> {
+        let list = values
+            .iter()
+            .map(|v| format!("0x{v:x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.execute(&format!("write_memory 0x{address:08x} {width} {{{list}}}")).map(|_| ())
+    }
+
+    /// Writes every value of `width` bits (8, 16 or 32) to the same register in one round trip.
+    /// Used for FIFO-like data registers.
+    pub fn write_repeated(&mut self, address: u32, width: u32, values: &[u32]) -> Result<(), TclError> {
+        let list = values
+            .iter()
+            .map(|v| format!("0x{v:x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let cmd = write_command(width)?;
+        self.execute(&format!("foreach _mik32_w {{{list}}} {{{cmd} 0x{address:08x} $_mik32_w}}")).map(|_| ())
+    }
+
+    /// Reads the same register `count` times in one round trip.
+    pub fn read_repeated(&mut self, address: u32, width: u32, count: u32) -> Result<Vec<u32>, TclError> {
+        let reply = self.execute(&format!(
+            "set _mik32_r {{}}; for {{set _mik32_i 0}} {{$_mik32_i < {count}}} {{incr _mik32_i}} \
+            {{lappend _mik32_r [read_memory 0x{address:08x} {width} 1]}}; join $_mik32_r"
+        ))?;
+        parse_values(&reply, count)
+    }
+
+    pub fn read_word(&mut self, address: u32) -> Result<u32, TclError> {
+        let values = self.read_memory(address, 32, 1)?;
+        values.first().copied().ok_or(TclError::BadReply)
+    }
+
+    /// Reads `count` values of `width` bits each (8, 16 or 32) starting from `address`.
+    pub fn read_memory(&mut self, address: u32, width: u32, count: u32) -> Result<Vec<u32>, TclError> {
+        let reply = self.execute(&format!("read_memory 0x{address:08x} {width} {count}"))?;
+        parse_values(&reply, count)
+    }
+
+    fn read_reply(&mut self) -> Result<String, TclError> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(end) = self.buffer.iter().position(|b| *b == TCL_TERMINATOR) {
+                let reply: Vec<u8> = self.buffer.drain(..=end).take(end).collect();
+                return String::from_utf8(reply).map_err(|_| TclError::BadReply);
+            }
+            let read = self.stream.read(&mut chunk)?;
+            if read == 0 {
+                return Err(TclError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+/// Splits `"<rc> <message>"` produced by the `catch` wrapper in `execute`.
+fn parse_reply(reply: &str) -> Result<String, TclError> {
+    let (rc, message) = reply.split_once(' ').unwrap_or((reply, ""));
+    match rc {
+        "0" => Ok(message.trim_end().to_owned()),
+        "1" | "2" | "3" | "4" => Err(TclError::CommandFailed(message.trim_end().to_owned())),
+        _ => Err(TclError::BadReply),
+    }
+}
+
+fn write_command(width: u32) -> Result<&'static str, TclError> {
+    match width {
+        8 => Ok("mwb"),
+        16 => Ok("mwh"),
+        32 => Ok("mww"),
+        _ => Err(TclError::CommandFailed(format!("unsupported access width {}", width))),
+    }
+}
+
+fn parse_values(reply: &str, count: u32) -> Result<Vec<u32>, TclError> {
+    let values = reply
+        .split_whitespace()
+        .map(parse_number)
+        .collect::<Option<Vec<_>>>()
+        .ok_or(TclError::BadReply)?;
+    if values.len() != count as usize {
+        return Err(TclError::BadReply);
+    }
+    Ok(values)
+}
+
+fn parse_number(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Fake openocd TCL server for tests of code that drives the target through `TclClient`.
+/// Understands the commands `TclClient` sends and passes memory accesses to a `Target` model.
+#[cfg(test)]
+pub mod fake {
+    use std::{io::{BufRead, BufReader, Write}, net::TcpListener, thread::JoinHandle, time::Duration};
+
+    use super::{parse_number, TclClient, TCL_TERMINATOR};
+
+    /// Memory map of a simulated chip. `width` is 8, 16 or 32 bits.
+    pub trait Target: Send + 'static {
+        fn read(&mut self, address: u32, width: u32) -> u32;
+        fn write(&mut self, address: u32, width: u32, value: u32);
+    }
+
+    /// Serves `target` on a free local port. The thread hands the target back once the client disconnects,
+    /// so a test can inspect what was written.
+    pub fn serve<T: Target>(mut target: T) -> (TclClient, JoinHandle<T>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            for frame in BufReader::new(stream).split(TCL_TERMINATOR).map_while(Result::ok) {
+                let frame = String::from_utf8(frame).unwrap();
+                let reply = match unwrap_catch(&frame).map(|command| run(&mut target, command)) {
+                    Some(Ok(result)) => format!("0 {}", result),
+                    Some(Err(message)) => format!("1 {}", message),
+                    None => "fake".to_owned(),
+                };
+                let mut reply = reply.into_bytes();
+                reply.push(TCL_TERMINATOR);
+                writer.write_all(&reply).unwrap();
+            }
+            target
+        });
+        let client = TclClient::connect("127.0.0.1", port, Duration::from_secs(1)).unwrap();
+        (client, server)
+    }
+
+    /// Runs `test` with a client connected to `target` served by `serve`, then disconnects
+    /// and returns the result together with the target.
+    pub fn session<T: Target, R>(target: T, test: impl FnOnce(&mut TclClient) -> R) -> (R, T) {
+        let (mut client, server) = serve(target);
+        let result = test(&mut client);
+        drop(client);
+        (result, server.join().unwrap())
+    }
+
+    /// Command inside the `catch` wrapper of `TclClient::execute`.
+    fn unwrap_catch(frame: &str) -> Option<&str> {
+        let command = frame.strip_prefix("set _mik32_rc [catch {")?;
+        Some(command.split_once("} _mik32_msg]")?.0)
+    }
+
+    fn run(target: &mut impl Target, command: &str) -> Result<String, String> {
+        if let Some(rest) = command.strip_prefix("foreach _mik32_w {") {
+            // write_repeated: foreach _mik32_w {values} {mwX address $_mik32_w}
+            let (values, body) = rest.split_once("} {").ok_or("bad foreach")?;
+            let mut body = body.split_whitespace();
+            let width = write_width(body.next().unwrap_or_default())?;
+            let address = number(body.next())?;
+            for value in values.split_whitespace() {
+                target.write(address, width, number(Some(value))?);
+            }
+            return Ok(String::new());
+        }
+        if command.starts_with("set _mik32_r {}") {
+            // read_repeated: ... {$_mik32_i < count} ... [read_memory address width 1] ...
+            let count = command.split_once("$_mik32_i < ").and_then(|(_, rest)| rest.split_once('}'));
+            let count = number(count.map(|(count, _)| count))?;
+            let read = command.split_once("[read_memory ").ok_or("bad read loop")?.1;
+            let mut read = read.split_whitespace();
+            let address = number(read.next())?;
+            let width = number(read.next())?;
+            let values: Vec<String> = (0..count).map(|_| format!("0x{:x}", target.read(address, width))).collect();
+            return Ok(values.join(" "));
+        }
+
+        let mut words = command.split_whitespace();
+        match words.next().unwrap_or_default() {
+            op @ ("mww" | "mwh" | "mwb") => {
+                let width = write_width(op)?;
+                let address = number(words.next())?;
+                target.write(address, width, number(words.next())?);
+                Ok(String::new())
+            }
+            "read_memory" => {
+                let address = number(words.next())?;
+                let width = number(words.next())?;
+                let count = number(words.next())?;
+                let values: Vec<String> = (0..count)
+                    .map(|i| format!("0x{:x}", target.read(address + i * width / 8, width)))
+                    .collect();
+                Ok(values.join(" "))
+            }
+            "write_memory" => {
+                let address = number(words.next())?;
+                let width = number(words.next())?;
+                for (i, value) in words.enumerate() {
+                    let value = number(Some(value.trim_matches(|c| c == '{' || c == '}')))?;
+                    target.write(address + i as u32 * width / 8, width, value);
+                }
+                Ok(String::new())
+            }
+            "reset" | "resume" | "reg" => Ok(String::new()),
+            other => Err(format!("invalid command name \"{}\"", other)),
+        }
+    }
+
+    fn write_width(op: &str) -> Result<u32, String> {
+        match op {
+            "mwb" => Ok(8),
+            "mwh" => Ok(16),
+            "mww" => Ok(32),
+            _ => Err(format!("invalid command name \"{}\"", op)),
+        }
+    }
+
+    fn number(value: Option<&str>) -> Result<u32, String> {
+        value.and_then(parse_number).ok_or_else(|| format!("expected a number, got {:?}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::{BufRead, BufReader, ErrorKind, Write}, net::{TcpListener, TcpStream}, thread::sleep, time::Duration};
+
+    use super::*;
+
+    /// Client connected to a server thread that runs `server` on the accepted connection.
+    fn client_with(server: impl FnOnce(TcpStream) + Send + 'static) -> TclClient {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || server(listener.accept().unwrap().0));
+        TclClient::connect("127.0.0.1", port, Duration::from_secs(1)).unwrap()
+    }
+
+    fn read_request(stream: &TcpStream) -> String {
+        let mut frame = Vec::new();
+        BufReader::new(stream).read_until(TCL_TERMINATOR, &mut frame).unwrap();
+        String::from_utf8(frame).unwrap()
+    }
+
+    #[test]
+    fn reply_split_across_reads() {
+        let mut client = client_with(|mut stream| {
+            read_request(&stream);
+            stream.write_all(b"0 Open On-").unwrap();
+            stream.flush().unwrap();
+            sleep(Duration::from_millis(50));
+            stream.write_all(b"Chip Debugger\x1a").unwrap();
+        });
+        assert_eq!(client.execute("version").unwrap(), "Open On-Chip Debugger");
+    }
+
+    #[test]
+    fn two_replies_in_one_read() {
+        let mut client = client_with(|mut stream| {
+            read_request(&stream);
+            stream.write_all(b"0 first\x1a0 second\x1a").unwrap();
+            // Keep the connection open until the client sent its second request.
+            read_request(&stream);
+        });
+        assert_eq!(client.execute("one").unwrap(), "first");
+        assert_eq!(client.execute("two").unwrap(), "second");
+    }
+
+    #[test]
+    fn failed_command() {
+        let mut client = client_with(|mut stream| {
+            read_request(&stream);
+            stream.write_all(b"1 invalid command name \"foo\"\x1a").unwrap();
+        });
+        match client.execute("foo") {
+            Err(TclError::CommandFailed(message)) => assert_eq!(message, "invalid command name \"foo\""),
+            other => panic!("expected CommandFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bad_return_code() {
+        let mut client = client_with(|mut stream| {
+            read_request(&stream);
+            stream.write_all(b"7 what\x1a").unwrap();
+        });
+        assert!(matches!(client.execute("version"), Err(TclError::BadReply)));
+    }
+
+    #[test]
+    fn connection_closed_mid_reply() {
+        let mut client = client_with(|mut stream| {
+            read_request(&stream);
+            stream.write_all(b"0 partial").unwrap();
+        });
+        match client.execute("version") {
+            Err(TclError::Io(e)) => assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+            other => panic!("expected UnexpectedEof, got {:?}", other),
+        }
+    }
+}
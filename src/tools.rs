@@ -0,0 +1,306 @@
+use std::{env, ffi::OsStr, path::{Path, PathBuf}, process::Command, sync::Mutex};
+
+/// External programs cargo-mik32 relies on. mik32-uploader is a directory rather than an executable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tool {
+    Openocd,
+    Python,
+    Uploader,
+    Gdb,
+    Objcopy,
+}
+
+impl Tool {
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Openocd => "openocd",
+            Tool::Python => "python3",
+            Tool::Uploader => "mik32-uploader",
+            Tool::Gdb => "gdb",
+            Tool::Objcopy => "cargo-objcopy",
+        }
+    }
+
+    pub fn env_var(self) -> Option<&'static str> {
+        match self {
+            Tool::Openocd => Some("MIK32_OPENOCD_PATH"),
+            Tool::Python => Some("MIK32_PYTHON"),
+            Tool::Uploader => Some("MIK32_UPLOADER_PATH"),
+            Tool::Gdb => Some("MIK32_GDB_EXEC"),
+            Tool::Objcopy => None,
+        }
+    }
+
+    /// Locations relative to the project directory, e.g. tools unpacked into ./flash.
+    fn project_candidates(self) -> &'static [&'static str] {
+        match self {
+            Tool::Openocd => &["flash/openocd/bin/openocd"],
+            Tool::Uploader => &["flash/mik32-uploader"],
+            Tool::Python | Tool::Gdb | Tool::Objcopy => &[],
+        }
+    }
+
+    fn path_candidates(self) -> &'static [&'static str] {
+        match self {
+            Tool::Openocd => &["openocd"],
+            Tool::Python => &["python3", "python"],
+            Tool::Uploader => &[],
+            Tool::Gdb => &["gdb-multiarch", "riscv64-unknown-elf-gdb", "riscv32-unknown-elf-gdb"],
+            Tool::Objcopy => &["cargo-objcopy"],
+        }
+    }
+}
+
+/// Where a tool was found.
+#[derive(Debug, Clone)]
+pub enum ToolSource {
+    /// Command line flag or project configuration.
+    Provided,
+    Env(&'static str),
+    ProjectDir,
+    Path,
+}
+
+impl std::fmt::Display for ToolSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ToolSource::Provided => write!(f, "provided"),
+            ToolSource::Env(var) => write!(f, "{}", var),
+            ToolSource::ProjectDir => write!(f, "project directory"),
+            ToolSource::Path => write!(f, "PATH"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResolvedTool {
+    pub path: PathBuf,
+    pub source: ToolSource,
+    /// First line of `--version` output.
+    pub version: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ToolError {
+    NotFound { tool: Tool, searched: Vec<String> },
+    Invalid { tool: Tool, path: PathBuf, reason: String },
+}
+
+impl std::fmt::Display for ToolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ToolError::NotFound { tool, searched } => {
+                write!(f, "{} not found, searched: {}", tool.name(), searched.join(", "))
+            }
+            ToolError::Invalid { tool, path, reason } => {
+                write!(f, "{} at {} is not usable: {}", tool.name(), path.display(), reason)
+            }
+        }
+    }
+}
+
+/// Tools resolved during this invocation, keyed by tool and provided value.
+static CACHE: Mutex<Vec<(Tool, Option<PathBuf>, ResolvedTool)>> = Mutex::new(Vec::new());
+
+/// Finds `tool` in order: `provided` value (command line or project config), environment variable,
+/// project directory, PATH. Provided values and environment variables must point to a working tool,
+/// they are not silently skipped. Results are cached for the rest of the invocation.
+pub fn resolve(tool: Tool, provided: Option<&Path>, project_dir: &Path) -> Result<ResolvedTool, ToolError> {
+    let provided = provided.map(Path::to_path_buf);
+    if let Some((_, _, resolved)) = CACHE
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(t, p, _)| *t == tool && *p == provided)
+    {
+        return Ok(resolved.clone());
+    }
+
+    let resolved = lookup(tool, provided.as_deref(), project_dir)?;
+    CACHE.lock().unwrap().push((tool, provided, resolved.clone()));
+    Ok(resolved)
+}
+
+fn lookup(tool: Tool, provided: Option<&Path>, project_dir: &Path) -> Result<ResolvedTool, ToolError> {
+    let env_value = tool.env_var().and_then(env::var_os);
+    search(tool, provided, env_value.as_deref(), project_dir, env::var_os("PATH").as_deref())
+}
+
+/// `lookup` with the tool's environment variable and PATH passed in.
+fn search(
+    tool: Tool,
+    provided: Option<&Path>,
+    env_value: Option<&OsStr>,
+    project_dir: &Path,
+    path_var: Option<&OsStr>,
+) -> Result<ResolvedTool, ToolError> {
+    if let Some(path) = provided {
+        return validate(tool, path, ToolSource::Provided);
+    }
+    if let Some(var) = tool.env_var()
+        && let Some(value) = env_value.filter(|v| !v.is_empty())
+    {
+        return validate(tool, Path::new(value), ToolSource::Env(var));
+    }
+
+    let mut searched = Vec::new();
+    if let Some(var) = tool.env_var() {
+        searched.push(var.to_owned());
+    }
+    for candidate in tool.project_candidates() {
+        let path = project_dir.join(candidate);
+        if (tool == Tool::Uploader && path.is_dir()) || is_executable(&path) {
+            return validate(tool, &path, ToolSource::ProjectDir);
+        }
+        searched.push(path.display().to_string());
+    }
+    for candidate in tool.path_candidates() {
+        if let Some(path) = find_in(candidate, path_var) {
+            return validate(tool, &path, ToolSource::Path);
+        }
+        searched.push(format!("{} in PATH", candidate));
+    }
+    Err(ToolError::NotFound { tool, searched })
+}
+
+fn validate(tool: Tool, path: &Path, source: ToolSource) -> Result<ResolvedTool, ToolError> {
+    let invalid = |reason: String| ToolError::Invalid { tool, path: path.to_path_buf(), reason };
+
+    if tool == Tool::Uploader {
+        if !path.join("mik32_upload.py").exists() {
+            return Err(invalid("no mik32_upload.py in it".to_owned()));
+        }
+        return Ok(ResolvedTool { path: path.to_path_buf(), source, version: None });
+    }
+
+    // Bare names, like `--gdb-exec gdb-multiarch`, are looked up in PATH.
+    let path = if path.components().count() == 1 && !path.exists() {
+        find_in_path(path.as_os_str()).ok_or_else(|| invalid("not found in PATH".to_owned()))?
+    } else {
+        path.to_path_buf()
+    };
+    if !is_executable(&path) {
+        return Err(invalid("not an executable file".to_owned()));
+    }
+    let version = version_of(&path).map_err(|e| invalid(e.to_string()))?;
+    Ok(ResolvedTool { path, source, version })
+}
+
+/// Searches PATH like a shell does. On Windows the executable suffix is appended when missing.
+pub fn find_in_path(name: impl AsRef<OsStr>) -> Option<PathBuf> {
+    find_in(name, env::var_os("PATH").as_deref())
+}
+
+fn find_in(name: impl AsRef<OsStr>, path_var: Option<&OsStr>) -> Option<PathBuf> {
+    let name = Path::new(name.as_ref());
+    env::split_paths(path_var?)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .flat_map(|dir| {
+            let path = dir.join(name);
+            let with_suffix = (path.extension().is_none() && !env::consts::EXE_SUFFIX.is_empty())
+                .then(|| path.with_extension(env::consts::EXE_EXTENSION));
+            [Some(path), with_suffix]
+        })
+        .flatten()
+        .find(|path| is_executable(path))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// First non-empty line of `--version` output. Some tools, like openocd, print it to stderr.
+fn version_of(path: &Path) -> std::io::Result<Option<String>> {
+    let output = Command::new(path).arg("--version").output()?;
+    let text = String::from_utf8_lossy(&output.stdout).into_owned() + &String::from_utf8_lossy(&output.stderr);
+    Ok(text.lines().map(str::trim).find(|l| !l.is_empty()).map(str::to_owned))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Scratch directory with fake tools, removed when dropped.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("cargo-mik32-tools-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+
+        /// Executable script answering `--version` with `version`.
+        fn tool(&self, relative: &str, version: &str) -> PathBuf {
+            let path = self.0.join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, format!("#!/bin/sh\necho '{}'\n", version)).unwrap();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            path
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn tools_are_searched_in_precedence_order() {
+        let scratch = Scratch::new("order");
+        let project = scratch.0.join("project");
+        let provided = scratch.tool("provided/openocd", "provided");
+        let from_env = scratch.tool("env/openocd", "env");
+        scratch.tool("project/flash/openocd/bin/openocd", "project");
+        scratch.tool("bin/openocd", "path");
+        let path_var = scratch.0.join("bin").into_os_string();
+
+        let found = |provided: Option<&Path>, env_value: Option<&OsStr>, project: &Path| {
+            search(Tool::Openocd, provided, env_value, project, Some(&path_var)).map(|tool| tool.version.unwrap())
+        };
+        let env_value = Some(from_env.as_os_str());
+        assert_eq!(found(Some(&provided), env_value, &project).unwrap(), "provided");
+        assert_eq!(found(None, env_value, &project).unwrap(), "env");
+        assert_eq!(found(None, Some(OsStr::new("")), &project).unwrap(), "project");
+        assert_eq!(found(None, None, &project).unwrap(), "project");
+        assert_eq!(found(None, None, &scratch.0.join("elsewhere")).unwrap(), "path");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn missing_tool_lists_every_place_searched() {
+        let scratch = Scratch::new("missing");
+        let result = search(Tool::Gdb, None, None, &scratch.0, Some(scratch.0.as_os_str()));
+        let Err(ToolError::NotFound { searched, .. }) = result else {
+            panic!("gdb should not be found");
+        };
+        assert_eq!(searched[0], "MIK32_GDB_EXEC");
+        assert!(searched.contains(&"gdb-multiarch in PATH".to_owned()));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn broken_provided_tool_is_not_skipped() {
+        let scratch = Scratch::new("broken");
+        scratch.tool("bin/openocd", "path");
+        let path_var = scratch.0.join("bin").into_os_string();
+        let missing = scratch.0.join("no-such-openocd");
+        let result = search(Tool::Openocd, Some(&missing), None, &scratch.0, Some(&path_var));
+        assert!(matches!(result, Err(ToolError::Invalid { .. })));
+    }
+}